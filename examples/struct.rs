@@ -1,11 +1,7 @@
 use rallo::RalloAllocator;
 
-// This is the maximum length of a frame
-const MAX_FRAME_LENGTH: usize = 128;
-// Maximum number of allocations to keep
-const MAX_LOG_COUNT: usize = 1_024 * 10;
 #[global_allocator]
-static ALLOCATOR: RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT> = RalloAllocator::new();
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
 
 struct Foo {
     a: Vec<u32>,
@@ -23,16 +19,12 @@ impl Foo {
 
 fn main() {
     let mut f = Foo::new();
-    // Safety: the program is single-threaded
-    unsafe { ALLOCATOR.start_track() };
+    let guard = ALLOCATOR.start().unwrap();
     for i in 0..100 {
         f.add(i);
     }
     f.a.shrink_to_fit();
-    ALLOCATOR.stop_track();
-
-    // Safety: it is called after `stop_track`
-    let stats = unsafe { ALLOCATOR.calculate_stats() };
+    let stats = guard.finish().unwrap();
     let tree = stats.into_tree().unwrap();
 
     let file_name = "struct-memory-flamegraph.html";
@@ -1,217 +1,1368 @@
 use std::{
-    alloc::{GlobalAlloc, Layout},
-    collections::VecDeque,
-    ffi::c_void,
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt::Display,
+    io::{self, Write},
     mem::MaybeUninit,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    stats::{Allocation, FrameInfo, Stats},
+    capture::{Capture, CaptureWriter, RawEvent, RawEventKind},
+    counters::{AtomicCounters, Counters},
+    fault::FaultInjection,
+    mmap,
+    region::{self, RegionPath},
+    sampling,
+    stacks::{StackId, StackTable},
+    stats::Stats,
+    symbolize,
+    thread::{self, THREAD_NAME_LENGTH},
     unsafe_cell::RalloUnsafeCell,
+    unwind::Unwinder,
 };
 
-#[derive(Default, Clone, Copy)]
-pub struct FrameWrapper {
-    pub ip: Option<usize>,
+thread_local! {
+    // Set while the current thread records an event: the allocations made
+    // while recording must not be recorded themselves.
+    static IS_LOGGING: Cell<bool> = const { Cell::new(false) };
 }
-impl FrameWrapper {
+
+/// Run `f`, unless the current thread is already recording an event.
+fn without_reentrancy<R, F: FnOnce() -> R>(f: F) -> Option<R> {
+    IS_LOGGING.with(|is_logging| {
+        if is_logging.replace(true) {
+            return None;
+        }
+        let result = f();
+        is_logging.set(false);
+        Some(result)
+    })
+}
+
+/// Run `f` without recording the events of the current thread, even if nested.
+/// For rallo's own work: helper threads, copies of the stats...
+pub(crate) fn unrecorded<R, F: FnOnce() -> R>(f: F) -> R {
+    /// Restores the flag, even if `f` panics
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            IS_LOGGING.with(|is_logging| is_logging.set(self.0));
+        }
+    }
+
+    let _restore = Restore(IS_LOGGING.with(|is_logging| is_logging.replace(true)));
+    f()
+}
+
+/// Whether the current thread is recording an event, or is rallo's writer thread
+fn is_recording() -> bool {
+    IS_LOGGING.with(Cell::get)
+}
+
+/// A single recorded event.
+struct LogEntry {
+    data: EventData,
+    /// Position of the event in its log, counting the events which overwrote the slot
+    index: usize,
+    /// `index + 1` once the event is completely written
+    committed: AtomicUsize,
+}
+
+/// The fields of an event. Plain data: a snapshot copies them out of the slot
+/// and only uses the copy once it checked that no thread overwrote it meanwhile.
+#[derive(Clone, Copy)]
+struct EventData {
+    /// Size of the block (the new size, for reallocations)
+    size: usize,
+    /// Size of the block before a reallocation
+    previous_size: usize,
+    /// Address of the block (the new address, for reallocations)
+    address: usize,
+    /// Address of the block before a reallocation
+    previous_address: usize,
+    /// How many events this one stands for, when sampling
+    weight: f64,
+    /// Position of the event among all the events of the session
+    sequence: u64,
+    /// Nanoseconds elapsed since the start of the session
+    timestamp: u64,
+    /// Identifier of the thread which produced the event
+    thread_id: u64,
+    /// Name of the thread, truncated to `THREAD_NAME_LENGTH` bytes
+    thread_name: [u8; THREAD_NAME_LENGTH],
+    thread_name_len: usize,
+    /// Regions the thread was in
+    region: RegionPath,
+    /// Interned stack, `None` if it didn't fit in the stack table
+    stack: Option<StackId>,
+}
+
+impl LogEntry {
+    /// Mark the event as completely written
+    fn commit(&self) {
+        self.committed.store(self.index + 1, Ordering::Release);
+    }
+}
+
+impl EventData {
+    fn raw_event(&self, kind: RawEventKind, stacks: &StackTable) -> RawEvent {
+        let (frames, truncated) = match self.stack {
+            Some(stack) => stacks.frames(stack),
+            None => (Vec::new(), true),
+        };
+        RawEvent {
+            kind,
+            size: self.size,
+            previous_size: self.previous_size,
+            address: self.address,
+            previous_address: self.previous_address,
+            weight: self.weight,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            thread_id: self.thread_id,
+            thread_name: self.thread_name(),
+            region: self
+                .region
+                .names()
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            frames,
+            truncated,
+        }
+    }
+
+    fn thread_name(&self) -> Option<String> {
+        if self.thread_name_len == 0 {
+            return None;
+        }
+        let name = &self.thread_name[..self.thread_name_len];
+        Some(String::from_utf8_lossy(name).into_owned())
+    }
+}
+
+type LogsType = &'static [RalloUnsafeCell<LogEntry>];
+
+/// What the allocator does with events that don't fit in its log buffers.
+///
+/// Whatever the policy, the number of lost events is reported
+/// in [`Stats::dropped_events`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Panic inside the allocator
+    #[default]
+    Panic,
+    /// Keep the oldest events and discard the new ones
+    DropNewest,
+    /// Overwrite the oldest events, keeping the last [`TrackOptions::max_log_count`] ones
+    RingBuffer,
+    /// Stop tracking at the first event that doesn't fit
+    StopTracking,
+}
+
+/// Sizes of the buffers where a session records its events.
+///
+/// ```rust
+/// use rallo::{RalloAllocator, TrackOptions};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+///
+/// let options = TrackOptions::new().max_frame_length(64).max_log_count(100_000);
+/// let stats = ALLOCATOR
+///     .track_with(options, || {
+///         let _ = String::with_capacity(1024);
+///     })
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct TrackOptions {
+    max_frame_length: usize,
+    skip_frames: usize,
+    unwinder: Unwinder,
+    max_log_count: usize,
+    max_stack_frames: usize,
+    memory_budget: Option<(usize, BudgetPolicy)>,
+    fault_injection: Option<FaultInjection>,
+    /// The only thread whose events are recorded, all of them if `None`
+    thread_id: Option<u64>,
+}
+
+impl TrackOptions {
+    /// Options with 128 frames per stack, 10240 events per kind and room
+    /// for a million frames of distinct stacks
     pub const fn new() -> Self {
-        FrameWrapper { ip: None }
+        TrackOptions {
+            max_frame_length: 128,
+            skip_frames: 0,
+            unwinder: Unwinder::Backtrace,
+            max_log_count: 1_024 * 10,
+            max_stack_frames: 1 << 20,
+            memory_budget: None,
+            fault_injection: None,
+            thread_id: None,
+        }
+    }
+
+    /// Maximum number of frames kept for each event: the innermost ones.
+    /// The deeper stacks are marked as [`crate::Allocation::truncated`]
+    pub const fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        assert!(max_frame_length > 0, "max frame length must be positive");
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// Leave out the `skip_frames` innermost frames of each stack, like the ones
+    /// of rallo and of the `GlobalAlloc` shims. They don't count in the
+    /// [`TrackOptions::max_frame_length`]
+    pub const fn skip_frames(mut self, skip_frames: usize) -> Self {
+        self.skip_frames = skip_frames;
+        self
+    }
+
+    /// How the stacks are captured, [`Unwinder::Backtrace`] by default.
+    ///
+    /// ```rust
+    /// use rallo::{RalloAllocator, TrackOptions, Unwinder};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// // With RUSTFLAGS="-C force-frame-pointers=yes"
+    /// let options = TrackOptions::new().unwinder(Unwinder::FramePointers);
+    /// let stats = ALLOCATOR
+    ///     .track_with(options, || {
+    ///         let _ = String::with_capacity(1024);
+    ///     })
+    ///     .unwrap();
+    /// ```
+    pub const fn unwinder(mut self, unwinder: Unwinder) -> Self {
+        self.unwinder = unwinder;
+        self
+    }
+
+    /// Maximum number of events of each kind (allocations, deallocations
+    /// and reallocations) kept for a session
+    pub const fn max_log_count(mut self, max_log_count: usize) -> Self {
+        assert!(max_log_count > 0, "max log count must be positive");
+        self.max_log_count = max_log_count;
+        self
+    }
+
+    /// Room for the frames of the distinct stacks of a session, shared by
+    /// all its events: each stack is stored once, however many events it
+    /// has, with a few words of overhead every 32 frames.
+    /// The events whose stack doesn't fit anymore are recorded with an
+    /// empty stack, marked as [`crate::Allocation::truncated`]
+    pub const fn max_stack_frames(mut self, max_stack_frames: usize) -> Self {
+        assert!(max_stack_frames > 0, "max stack frames must be positive");
+        self.max_stack_frames = max_stack_frames;
+        self
+    }
+
+    /// Refuse the allocations which would bring the bytes allocated by the session,
+    /// net of the ones it freed, over `bytes`.
+    ///
+    /// Concurrent allocations are checked independently: they can exceed the
+    /// budget by a few bytes.
+    pub const fn memory_budget(mut self, bytes: usize, policy: BudgetPolicy) -> Self {
+        self.memory_budget = Some((bytes, policy));
+        self
+    }
+
+    /// Make some allocations fail, to exercise the code handling them
+    /// (`try_reserve` and the like). The failures are reported by
+    /// [`Stats::failed_allocations`].
+    ///
+    /// ```rust
+    /// use rallo::{FaultInjection, RalloAllocator, TrackOptions};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// let options = TrackOptions::new().fail_allocations(FaultInjection::Size(|size| size > 4096));
+    /// let mut result = Ok(());
+    /// let stats = ALLOCATOR
+    ///     .track_with(options, || result = Vec::<u8>::new().try_reserve(8192))
+    ///     .unwrap();
+    ///
+    /// assert!(result.is_err());
+    /// assert_eq!(stats.failed_allocations[0].allocation_size, 8192);
+    /// ```
+    pub const fn fail_allocations(mut self, fault_injection: FaultInjection) -> Self {
+        self.fault_injection = Some(fault_injection);
+        self
+    }
+
+    /// Record only the events of the current thread: the other threads can't
+    /// fill the buffers. The session counters still count all of them
+    pub(crate) fn current_thread_only(mut self) -> Self {
+        self.thread_id = Some(thread::current_thread_id());
+        self
+    }
+
+    fn same_buffers(&self, other: &TrackOptions) -> bool {
+        self.max_log_count == other.max_log_count && self.max_stack_frames == other.max_stack_frames
     }
 }
 
-type LogType = (usize, usize, usize, &'static mut [FrameWrapper]);
-type LogsType = &'static [RalloUnsafeCell<LogType>];
+/// What to do with an allocation exceeding the [`TrackOptions::memory_budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    /// Return a null pointer: the caller sees an allocation failure, and usually
+    /// calls [`std::alloc::handle_alloc_error`]
+    #[default]
+    ReturnNull,
+    /// Panic with the backtrace of the offending allocation, and stop enforcing
+    /// the budget for the session.
+    ///
+    /// The panic unwinds out of [`GlobalAlloc::alloc`]: a caller of the allocator's
+    /// methods can catch it, but the functions of the global allocator can't
+    /// unwind, so through `#[global_allocator]` it aborts the process once the
+    /// message is printed
+    Panic,
+}
+
+impl Default for TrackOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A fixed-size buffer of events with its write cursor.
+struct EventLog {
+    /// Replaced between sessions, when no event is being recorded
+    logs: RalloUnsafeCell<MaybeUninit<LogsType>>,
+    pointer: AtomicUsize,
+    /// Events handed to the writer, when streaming
+    consumed: AtomicUsize,
+    /// Events lost because the writer didn't keep up, when streaming
+    stream_dropped: AtomicUsize,
+}
+
+impl EventLog {
+    const fn new() -> Self {
+        EventLog {
+            logs: RalloUnsafeCell::new(MaybeUninit::uninit()),
+            pointer: AtomicUsize::new(0),
+            consumed: AtomicUsize::new(0),
+            stream_dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reset the cursors for a new session
+    ///
+    /// # Safety
+    ///
+    /// `logs` must have been initialized and no event can be recorded concurrently.
+    unsafe fn reset(&self) {
+        let logs = unsafe { self.logs.assume_init_ref() };
+        // Markers left by the previous session could look committed
+        for log in logs.iter() {
+            log.committed.store(0, Ordering::Relaxed);
+        }
+        self.pointer.store(0, Ordering::SeqCst);
+        self.consumed.store(0, Ordering::SeqCst);
+        self.stream_dropped.store(0, Ordering::SeqCst);
+    }
+
+    /// Reserve a slot for a streamed event, unless the writer is `capacity` events behind
+    fn reserve_streamed(&self, capacity: usize) -> Option<usize> {
+        let mut index = self.pointer.load(Ordering::SeqCst);
+        loop {
+            if index - self.consumed.load(Ordering::Acquire) >= capacity {
+                self.stream_dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            match self.pointer.compare_exchange_weak(
+                index,
+                index + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(index),
+                Err(actual) => index = actual,
+            }
+        }
+    }
+
+    /// Write the committed events not streamed yet, stopping at the first one
+    /// still being recorded. Returns how many were written.
+    ///
+    /// # Safety
+    ///
+    /// `logs` must have been initialized, and this must be the only consumer.
+    unsafe fn drain<W: Write>(
+        &self,
+        capacity: usize,
+        kind: RawEventKind,
+        stacks: &StackTable,
+        writer: &mut CaptureWriter<W>,
+    ) -> io::Result<usize> {
+        let mut written = 0;
+        loop {
+            let index = self.consumed.load(Ordering::SeqCst);
+            if index == self.pointer.load(Ordering::SeqCst) {
+                return Ok(written);
+            }
+            let log = unsafe { self.get(index % capacity) };
+            if log.committed.load(Ordering::Acquire) != index + 1 {
+                return Ok(written);
+            }
+            // The slot is not reused before `consumed` moves past it
+            writer.write_event(&log.data.raw_event(kind, stacks))?;
+            // Release the slot to the recording threads
+            self.consumed.store(index + 1, Ordering::Release);
+            written += 1;
+        }
+    }
+
+    /// Events lost by a stream, including the ones still being recorded when it stopped
+    fn stream_dropped(&self) -> usize {
+        self.stream_dropped.load(Ordering::SeqCst)
+            + (self.pointer.load(Ordering::SeqCst) - self.consumed.load(Ordering::SeqCst))
+    }
+
+    /// Map the buffer for `max_log_count` events, their stacks are in the `StackTable`.
+    /// It doesn't come from the global allocator: it never shows up in the stats.
+    fn allocate_logs(max_log_count: usize) -> LogsType {
+        let logs: *mut RalloUnsafeCell<LogEntry> = mmap::map(max_log_count);
+
+        for i in 0..max_log_count {
+            let entry = RalloUnsafeCell::new(LogEntry {
+                data: EventData {
+                    size: 0,
+                    previous_size: 0,
+                    address: 0,
+                    previous_address: 0,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: 0,
+                    thread_id: 0,
+                    thread_name: [0; THREAD_NAME_LENGTH],
+                    thread_name_len: 0,
+                    region: RegionPath::EMPTY,
+                    stack: None,
+                },
+                index: 0,
+                committed: AtomicUsize::new(0),
+            });
+            // Safety: every pointer is within the mapped buffer, and written once
+            unsafe { logs.add(i).write(entry) };
+        }
+
+        unsafe { std::slice::from_raw_parts(logs, max_log_count) }
+    }
+
+    /// Free the buffers returned by `allocate_logs`.
+    ///
+    /// # Safety
+    ///
+    /// `logs` must come from `allocate_logs` and must not be used anymore.
+    unsafe fn free_logs(logs: LogsType) {
+        unsafe {
+            mmap::unmap(logs.as_ptr() as *mut RalloUnsafeCell<LogEntry>, logs.len());
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get_mut(&self, index: usize) -> &mut LogEntry {
+        let logs = unsafe { self.logs.assume_init_ref() };
+        let element = &logs[index];
+        unsafe { &mut *element.get() }
+    }
+
+    unsafe fn get(&self, index: usize) -> &LogEntry {
+        let logs = unsafe { self.logs.assume_init_ref() };
+        let element = &logs[index];
+        unsafe { &*element.get() }
+    }
+
+    /// Number of events which didn't fit in the buffer.
+    fn dropped(&self, capacity: usize) -> usize {
+        self.pointer.load(Ordering::SeqCst).saturating_sub(capacity)
+    }
+
+    /// Copy the events completely written so far to `events`, while other
+    /// threads keep recording. `wraps` tells whether new events overwrite the old ones.
+    ///
+    /// # Safety
+    ///
+    /// `logs` must have been initialized, and must not be freed during the call.
+    unsafe fn snapshot(
+        &self,
+        kind: RawEventKind,
+        wraps: bool,
+        stacks: &StackTable,
+        events: &mut Vec<RawEvent>,
+    ) {
+        let capacity = unsafe { self.logs.assume_init_ref() }.len();
+        let pointer = self.pointer.load(Ordering::SeqCst);
+        let first = if wraps {
+            pointer.saturating_sub(capacity)
+        } else {
+            0
+        };
+
+        for index in first..pointer.min(first + capacity) {
+            let log = unsafe { self.get(index % capacity) };
+            // Seqlock read: the copy is valid if the slot held the same
+            // committed event before and after it. Nothing is read through
+            // its fields (region names, stack) before that is checked
+            if log.committed.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            // Safety: the slot stays mapped, a torn copy is discarded below
+            let data = unsafe { std::ptr::read_volatile(&log.data) };
+            atomic::fence(Ordering::Acquire);
+            if log.committed.load(Ordering::Relaxed) != index + 1 {
+                continue;
+            }
+            events.push(data.raw_event(kind, stacks));
+        }
+    }
+}
+
+/// Readers-writer spin lock on the log buffers: snapshots read them while the
+/// sessions can free or replace them.
+struct BuffersLock(AtomicUsize);
+
+/// Set in `BuffersLock` while the buffers are replaced; the other bits count the readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+impl BuffersLock {
+    const fn new() -> Self {
+        BuffersLock(AtomicUsize::new(0))
+    }
+
+    fn read<R>(&self, f: impl FnOnce() -> R) -> R {
+        let mut current = self.0.load(Ordering::SeqCst);
+        loop {
+            if current & WRITER != 0 {
+                std::thread::yield_now();
+                current = self.0.load(Ordering::SeqCst);
+                continue;
+            }
+            match self.0.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        let _unlock = Unlock(&self.0, 1);
+        f()
+    }
+
+    fn write<R>(&self, f: impl FnOnce() -> R) -> R {
+        while self
+            .0
+            .compare_exchange_weak(0, WRITER, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            std::thread::yield_now();
+        }
+        let _unlock = Unlock(&self.0, WRITER);
+        f()
+    }
+}
+
+/// Releases a `BuffersLock`, or a recorder of `RalloAllocator::record`, even if
+/// the code holding it panics
+struct Unlock<'a>(&'a AtomicUsize, usize);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(self.1, Ordering::SeqCst);
+    }
+}
+
+// Lifecycle of a tracking session
+const IDLE: u8 = 0;
+const TRACKING: u8 = 1;
+const STOPPED: u8 = 2;
+const COLLECTING: u8 = 3;
 
 /// A custom allocator that tracks memory allocations and deallocations.
+///
+/// Each kind of event is kept in a buffer sized by the [`TrackOptions`] of the
+/// session; what happens when one is full depends on the [`OverflowPolicy`]
+/// chosen at construction.
 /// ```rust
 /// use rallo::RalloAllocator;
 ///
-/// const MAX_FRAME_LENGTH: usize = 128;
-/// const MAX_LOG_COUNT: usize = 1_024 * 10;
 /// #[global_allocator]
-/// static ALLOCATOR: RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT> = RalloAllocator::new();
+/// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
 ///
 /// fn foo() {
 ///     let _ = String::with_capacity(1024);
 /// }
 ///
-/// unsafe { ALLOCATOR.start_track() };
-/// foo();
-/// ALLOCATOR.stop_track();
-///
-/// // Safety: it is called after `stop_track`
-/// let stats = unsafe { ALLOCATOR.calculate_stats() };
+/// let stats = ALLOCATOR.track(foo).unwrap();
 /// let tree = stats.into_tree().unwrap();
 ///
 /// tree.print_flamegraph("flamegraph-like-page.html");
 ///
 /// ```
-pub struct RalloAllocator<const MAX_FRAME_LENGTH: usize, const MAX_LOG_COUNT: usize> {
+///
+/// By default the memory comes from [`System`]; use [`RalloAllocator::with_allocator`]
+/// to wrap another allocator.
+pub struct RalloAllocator<A = System> {
     is_tracking: AtomicBool,
-    alloc: std::alloc::System,
-    allocation_logs: MaybeUninit<LogsType>,
-    allocation_logs_pointer: AtomicUsize,
-    deallocation_logs: MaybeUninit<LogsType>,
-    deallocation_logs_pointer: AtomicUsize,
+    /// Threads which saw `is_tracking` set and may still be writing an event:
+    /// the buffers are only read in full, reset or freed once they are done
+    recorders: AtomicUsize,
+    /// Whether the log buffers are allocated
+    has_buffers: AtomicBool,
+    /// Held by the snapshots while they read the buffers
+    buffers_lock: BuffersLock,
+    /// Lifecycle of the session, see `IDLE` and the following constants
+    state: AtomicU8,
+    /// Options of the current session, replaced when no event is being recorded
+    options: RalloUnsafeCell<TrackOptions>,
+    /// Whether the current session streams its events, see [`RalloAllocator::stream`]
+    streaming: AtomicBool,
+    /// Memory budget of the current session, `usize::MAX` if none
+    budget: AtomicUsize,
+    /// Whether exceeding the budget panics, see [`BudgetPolicy::Panic`]. Kept
+    /// apart from the options: it is read outside of `record`
+    budget_panics: AtomicBool,
+    /// Allocations and reallocations considered for fault injection in the session
+    fault_candidates: AtomicU64,
+    overflow_policy: OverflowPolicy,
+    sampling_rate: Option<usize>,
+    /// When the current session started
+    started_at: RalloUnsafeCell<Option<Instant>>,
+    /// Next sequence number, shared by all kinds of events
+    sequence: AtomicU64,
+    /// Always-on totals, see [`RalloAllocator::counters`]
+    counters: AtomicCounters,
+    /// Same as `counters`, for the events of the current session only
+    session_counters: AtomicCounters,
+    /// The allocator which actually provides the memory
+    alloc: A,
+    allocation_logs: EventLog,
+    deallocation_logs: EventLog,
+    reallocation_logs: EventLog,
+    failure_logs: EventLog,
+    /// Stacks of the events of all the logs
+    stacks: RalloUnsafeCell<StackTable>,
 }
-impl<const MAX_FRAME_LENGTH: usize, const MAX_LOG_COUNT: usize> Default
-    for RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT>
-{
+impl<A: GlobalAlloc + Default> Default for RalloAllocator<A> {
     fn default() -> Self {
-        Self::new()
+        Self::with_allocator(A::default())
     }
 }
 
-impl<const MAX_FRAME_LENGTH: usize, const MAX_LOG_COUNT: usize>
-    RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT>
-{
+impl RalloAllocator {
     pub const fn new() -> Self {
+        Self::with_allocator(System)
+    }
+
+    /// Create an allocator which handles the events exceeding
+    /// [`TrackOptions::max_log_count`] according to `overflow_policy`.
+    pub const fn with_overflow_policy(overflow_policy: OverflowPolicy) -> Self {
+        Self::new().overflow_policy(overflow_policy)
+    }
+}
+
+impl<A: GlobalAlloc> RalloAllocator<A> {
+    /// Create an allocator which tracks the memory provided by `alloc`.
+    ///
+    /// ```rust
+    /// use std::alloc::System;
+    /// use rallo::{OverflowPolicy, RalloAllocator};
+    ///
+    /// // Any `GlobalAlloc` works, e.g. `tikv_jemallocator::Jemalloc`
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator<System> =
+    ///     RalloAllocator::with_allocator(System).overflow_policy(OverflowPolicy::RingBuffer);
+    /// ```
+    pub const fn with_allocator(alloc: A) -> Self {
         RalloAllocator {
             is_tracking: AtomicBool::new(false),
-            alloc: std::alloc::System,
-            allocation_logs: MaybeUninit::uninit(),
-            allocation_logs_pointer: AtomicUsize::new(0),
-            deallocation_logs: MaybeUninit::uninit(),
-            deallocation_logs_pointer: AtomicUsize::new(0),
+            recorders: AtomicUsize::new(0),
+            has_buffers: AtomicBool::new(false),
+            buffers_lock: BuffersLock::new(),
+            state: AtomicU8::new(IDLE),
+            options: RalloUnsafeCell::new(TrackOptions::new()),
+            streaming: AtomicBool::new(false),
+            budget: AtomicUsize::new(usize::MAX),
+            budget_panics: AtomicBool::new(false),
+            fault_candidates: AtomicU64::new(0),
+            overflow_policy: OverflowPolicy::Panic,
+            sampling_rate: None,
+            started_at: RalloUnsafeCell::new(None),
+            sequence: AtomicU64::new(0),
+            counters: AtomicCounters::new(),
+            session_counters: AtomicCounters::new(),
+            alloc,
+            allocation_logs: EventLog::new(),
+            deallocation_logs: EventLog::new(),
+            reallocation_logs: EventLog::new(),
+            failure_logs: EventLog::new(),
+            stacks: RalloUnsafeCell::new(StackTable::new()),
+        }
+    }
+
+    /// Snapshot of the totals kept for every event since the program started,
+    /// whether a session is running or not.
+    ///
+    /// ```rust
+    /// use rallo::RalloAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// let before = ALLOCATOR.counters();
+    /// let v = vec![0_u8; 1024];
+    /// let after = ALLOCATOR.counters();
+    /// assert!(after.total_bytes_allocated - before.total_bytes_allocated >= 1024);
+    /// # drop(v);
+    /// ```
+    pub fn counters(&self) -> Counters {
+        self.counters.snapshot()
+    }
+
+    /// Handle the events exceeding [`TrackOptions::max_log_count`]
+    /// according to `overflow_policy`.
+    pub const fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Record only a statistical sample of the events: each allocated byte is sampled
+    /// with probability `1 / sampling_rate`, and an event is recorded if it contains
+    /// a sampled byte. Large events are always recorded, small ones rarely.
+    ///
+    /// Recorded events are weighted so that [`Stats`] reports unbiased estimates
+    /// of the real sizes and counts. This avoids paying for a backtrace on
+    /// every allocation.
+    pub const fn sampling_rate(mut self, sampling_rate: usize) -> Self {
+        assert!(sampling_rate > 0, "sampling rate must be positive");
+        self.sampling_rate = Some(sampling_rate);
+        self
+    }
+
+    /// Run `f` recording its allocations, and return their statistics.
+    ///
+    /// Fails if a session is already running, as for [`RalloAllocator::start`].
+    pub fn track<F: FnOnce()>(&self, f: F) -> Result<Stats, TrackingError> {
+        self.track_with(TrackOptions::new(), f)
+    }
+
+    /// Same as [`RalloAllocator::track`], with custom buffer sizes.
+    pub fn track_with<F: FnOnce()>(
+        &self,
+        options: TrackOptions,
+        f: F,
+    ) -> Result<Stats, TrackingError> {
+        let guard = self.start_with(options)?;
+        f();
+        guard.finish()
+    }
+
+    /// Start recording allocations until the returned guard is finished or dropped.
+    ///
+    /// Events left by a previous session and not collected yet are discarded.
+    /// Fails with [`TrackingError::AlreadyTracking`] if a session is already running,
+    /// or with [`TrackingError::Collecting`] if the stats of the previous one are
+    /// being collected.
+    pub fn start(&self) -> Result<TrackingGuard<'_, A>, TrackingError> {
+        self.start_with(TrackOptions::new())
+    }
+
+    /// Same as [`RalloAllocator::start`], with custom buffer sizes.
+    pub fn start_with(&self, options: TrackOptions) -> Result<TrackingGuard<'_, A>, TrackingError> {
+        self.transition(&[IDLE, STOPPED], TRACKING)?;
+
+        // Safety: the state machine guarantees no other session is starting,
+        // recording or being collected
+        unsafe { self.start_track_with(options) };
+
+        Ok(TrackingGuard { allocator: self })
+    }
+
+    /// Start recording allocations, streaming the events to `writer` until the
+    /// returned guard is finished or dropped. Long sessions are not limited by
+    /// the size of the buffers: a background thread writes the events as they come.
+    ///
+    /// The events are written unsymbolized: read them back with [`crate::Capture`].
+    /// The buffers only need to absorb the bursts the writer can't keep up with;
+    /// the events which don't fit are dropped, whatever the [`OverflowPolicy`].
+    ///
+    /// ```rust
+    /// use rallo::{Capture, RalloAllocator, TrackOptions};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// let guard = ALLOCATOR.stream(Vec::new(), TrackOptions::new()).unwrap();
+    /// let _ = String::with_capacity(1024);
+    /// let capture = guard.finish().unwrap();
+    ///
+    /// let stats = Capture::read(capture.as_slice()).unwrap().into_stats();
+    /// ```
+    pub fn stream<W: Write + Send + 'static>(
+        &'static self,
+        writer: W,
+        options: TrackOptions,
+    ) -> Result<StreamingGuard<W, A>, TrackingError>
+    where
+        A: Sync,
+    {
+        self.transition(&[IDLE, STOPPED], TRACKING)?;
+        self.streaming.store(true, Ordering::SeqCst);
+
+        // Safety: the state machine guarantees no other session is starting,
+        // recording or being collected
+        unsafe { self.start_track_with(options) };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        // Spawning the writer allocates: don't record it
+        let handle = unrecorded(|| {
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("rallo-writer".into())
+                // The allocations of the writer are not recorded either
+                .spawn(move || unrecorded(|| self.write_stream(writer, &stop)))
+                .expect("failed to spawn the rallo writer thread")
+        });
+
+        Ok(StreamingGuard {
+            allocator: self,
+            stop,
+            writer: Some(handle),
+        })
+    }
+
+    /// Body of the writer thread of [`RalloAllocator::stream`]
+    fn write_stream<W: Write>(&self, writer: W, stop: &AtomicBool) -> io::Result<W> {
+        let modules = symbolize::current_modules();
+        let mut writer = CaptureWriter::new(writer, self.sampling_rate, &modules)?;
+        let capacity = self.options.max_log_count;
+        let logs = [
+            (&self.allocation_logs, RawEventKind::Allocation),
+            (&self.deallocation_logs, RawEventKind::Deallocation),
+            (&self.reallocation_logs, RawEventKind::Reallocation),
+            (&self.failure_logs, RawEventKind::FailedAllocation),
+        ];
+
+        loop {
+            // Checked before draining: the last round sees every event committed in time
+            let stopping = stop.load(Ordering::SeqCst);
+            if stopping {
+                self.wait_for_recorders();
+            }
+            let mut written = 0;
+            for (log, kind) in logs {
+                // Safety: the buffers live until the stream is finished, and this
+                // thread is their only consumer
+                written += unsafe { log.drain(capacity, kind, &self.stacks, &mut writer)? };
+            }
+            if written == 0 {
+                if stopping {
+                    break;
+                }
+                writer.flush()?;
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        let dropped_events = logs.iter().map(|(log, _)| log.stream_dropped()).sum();
+        writer.finish(dropped_events, self.session_counters.snapshot())
+    }
+
+    /// Calculate the statistics of the last session, once it is stopped.
+    ///
+    /// Fails with [`TrackingError::StillTracking`] if the session is still running,
+    /// with [`TrackingError::NotStarted`] if there is no session to collect, or with
+    /// [`TrackingError::Collecting`] if another thread is collecting it.
+    pub fn collect(&self) -> Result<Stats, TrackingError> {
+        if self.streaming.load(Ordering::SeqCst) {
+            return Err(TrackingError::StillTracking);
+        }
+        self.transition(&[STOPPED], COLLECTING)?;
+        // Safety: the session is stopped and no other thread is collecting it
+        let stats = unsafe { self.calculate_stats() };
+        Ok(stats)
+    }
+
+    /// Same as [`RalloAllocator::collect`], without symbolizing the events.
+    ///
+    /// The capture can be saved with [`Capture::write`] and symbolized later, in
+    /// this process with [`Capture::into_stats`] or anywhere the executable files
+    /// are available with [`crate::Symbolizer`].
+    pub fn collect_capture(&self) -> Result<Capture, TrackingError> {
+        if self.streaming.load(Ordering::SeqCst) {
+            return Err(TrackingError::StillTracking);
+        }
+        self.transition(&[STOPPED], COLLECTING)?;
+        // Safety: the session is stopped and no other thread is collecting it
+        let mut capture = unsafe { self.take_capture() };
+        capture.modules = symbolize::current_modules();
+        Ok(capture)
+    }
+
+    /// Copy the statistics of the events recorded so far, without stopping the
+    /// session nor discarding its events: the following snapshots and the final
+    /// collection still see them. Useful to report periodically on a long session.
+    ///
+    /// The events being recorded by other threads during the snapshot are left out.
+    /// With the [`OverflowPolicy::RingBuffer`] policy, or when streaming, only the
+    /// events still in the buffers are returned.
+    /// Fails with [`TrackingError::NotStarted`] if no session was started, or with
+    /// [`TrackingError::Collecting`] if the stats are being collected.
+    ///
+    /// ```rust
+    /// use rallo::RalloAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// let guard = ALLOCATOR.start().unwrap();
+    /// let v = vec![0_u8; 1024];
+    /// let snapshot = ALLOCATOR.snapshot().unwrap();
+    /// assert!(snapshot.allocations.iter().any(|a| a.allocation_size == 1024));
+    /// # drop(v);
+    /// # guard.finish().unwrap();
+    /// ```
+    pub fn snapshot(&self) -> Result<Stats, TrackingError> {
+        self.buffers_lock
+            .read(|| {
+                match self.state.load(Ordering::SeqCst) {
+                    TRACKING | STOPPED if self.has_buffers.load(Ordering::SeqCst) => {}
+                    COLLECTING => return Err(TrackingError::Collecting),
+                    _ => return Err(TrackingError::NotStarted),
+                }
+
+                // The copies made by the snapshot are not recorded.
+                // Safety: the lock keeps the buffers alive
+                Ok(unrecorded(|| unsafe { self.snapshot_capture() }))
+            })
+            // Symbolized once the buffers are released
+            .map(|capture| unrecorded(|| capture.into_stats()))
+    }
+
+    /// # Safety
+    ///
+    /// The buffers must be initialized, and must not be freed during the call.
+    unsafe fn snapshot_capture(&self) -> Capture {
+        let streaming = self.streaming.load(Ordering::SeqCst);
+        let wraps = streaming || self.overflow_policy == OverflowPolicy::RingBuffer;
+        let logs = [
+            &self.allocation_logs,
+            &self.deallocation_logs,
+            &self.reallocation_logs,
+            &self.failure_logs,
+        ];
+        let dropped_events = logs
+            .iter()
+            .map(|log| {
+                if streaming {
+                    log.stream_dropped.load(Ordering::SeqCst)
+                } else {
+                    let capacity = unsafe { log.logs.assume_init_ref() }.len();
+                    log.dropped(capacity)
+                }
+            })
+            .sum();
+
+        let mut events = Vec::new();
+        let stacks = &self.stacks;
+        unsafe {
+            self.allocation_logs
+                .snapshot(RawEventKind::Allocation, wraps, stacks, &mut events);
+            self.deallocation_logs
+                .snapshot(RawEventKind::Deallocation, wraps, stacks, &mut events);
+            self.reallocation_logs
+                .snapshot(RawEventKind::Reallocation, wraps, stacks, &mut events);
+            self.failure_logs
+                .snapshot(RawEventKind::FailedAllocation, wraps, stacks, &mut events);
+        }
+        Capture {
+            modules: Vec::new(),
+            events,
+            dropped_events,
+            sampling_rate: self.sampling_rate,
+            counters: self.session_counters.snapshot(),
+        }
+    }
+
+    /// Move the session to `to`, if it is in one of the states `from`.
+    fn transition(&self, from: &[u8], to: u8) -> Result<(), TrackingError> {
+        let mut current = self.state.load(Ordering::SeqCst);
+        loop {
+            if !from.contains(&current) {
+                return Err(match current {
+                    TRACKING if to == TRACKING => TrackingError::AlreadyTracking,
+                    TRACKING => TrackingError::StillTracking,
+                    COLLECTING => TrackingError::Collecting,
+                    _ => TrackingError::NotStarted,
+                });
+            }
+            match self
+                .state
+                .compare_exchange(current, to, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Free the buffers where the events are recorded.
+    ///
+    /// They are allocated by the first session and reused by the following ones
+    /// with the same [`TrackOptions`]; the next session allocates them again.
+    /// Events not collected yet are discarded.
+    /// Fails with [`TrackingError::StillTracking`] if a session is running, or with
+    /// [`TrackingError::Collecting`] if the stats are being collected.
+    pub fn release_buffers(&self) -> Result<(), TrackingError> {
+        self.transition(&[IDLE, STOPPED], COLLECTING)?;
+        self.wait_for_recorders();
+
+        // Safety: the state machine guarantees no session is using the buffers,
+        // the wait that no event is still being written, and the lock that no
+        // snapshot is reading them
+        self.buffers_lock.write(|| unsafe { self.free_buffers() });
+
+        self.state.store(IDLE, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// Nobody must be using the buffers.
+    unsafe fn free_buffers(&self) {
+        if self.has_buffers.swap(false, Ordering::SeqCst) {
+            // Safety: the buffers were initialized by `start_track`
+            unsafe {
+                EventLog::free_logs(self.allocation_logs.logs.assume_init());
+                EventLog::free_logs(self.deallocation_logs.logs.assume_init());
+                EventLog::free_logs(self.reallocation_logs.logs.assume_init());
+                EventLog::free_logs(self.failure_logs.logs.assume_init());
+                self.stacks.free();
+            }
         }
     }
 
     /// Start recording allocations.
     ///
+    /// Events left by a previous session and not collected yet are discarded.
+    /// Prefer [`RalloAllocator::start`] or [`RalloAllocator::track`], which detect misuses.
+    ///
     /// # Safety
     ///
     /// It is the caller's responsibility to ensure that `start_track`
     /// is not called concurrently.
     pub unsafe fn start_track(&self) {
+        unsafe { self.start_track_with(TrackOptions::new()) }
+    }
+
+    /// # Safety
+    ///
+    /// Same as `start_track`.
+    unsafe fn start_track_with(&self, options: TrackOptions) {
         // Ask the backtrace to allow the backtrace system inizialization
         // without tracking it.
         backtrace::trace(|_| true);
 
-        let mut v = Vec::with_capacity(MAX_LOG_COUNT);
-        for _ in 0..MAX_LOG_COUNT {
-            let mut a = Vec::with_capacity(MAX_FRAME_LENGTH);
-            for _ in 0..MAX_FRAME_LENGTH {
-                a.push(FrameWrapper::new());
-            }
-
-            v.push(RalloUnsafeCell::new((
-                0,                               // size
-                0,                               // `backtrace` len (stack depth)
-                0,                               // ptr address
-                Box::leak(a.into_boxed_slice()), // frames
-            )));
-        }
-        let alloc: LogsType = Box::leak(v.into_boxed_slice());
+        // The events of the previous session must be completely written
+        self.wait_for_recorders();
 
-        let mut v = Vec::with_capacity(MAX_LOG_COUNT);
-        for _ in 0..MAX_LOG_COUNT {
-            let mut a = Vec::with_capacity(MAX_FRAME_LENGTH);
-            for _ in 0..MAX_FRAME_LENGTH {
-                a.push(FrameWrapper::new());
+        // Safety: `start_track` is not called concurrently, and no event is
+        // being recorded: nobody else reads the fields replaced below, and the
+        // lock keeps the snapshots away from the buffers
+        self.buffers_lock.write(|| unsafe {
+            // The buffers are allocated by the first session and reused by the next ones,
+            // as long as they ask for the same sizes
+            if self.has_buffers.load(Ordering::SeqCst) && !self.options.same_buffers(&options) {
+                self.free_buffers();
             }
-            v.push(RalloUnsafeCell::new((
-                0,                               // size
-                0,                               // `backtrace` len (stack depth)
-                0,                               // ptr address
-                Box::leak(a.into_boxed_slice()), // frames
-            )));
-        }
-        let dealloc: LogsType = Box::leak(v.into_boxed_slice());
+            if !self.has_buffers.load(Ordering::SeqCst) {
+                let max_log_count = options.max_log_count;
+                let alloc = EventLog::allocate_logs(max_log_count);
+                let dealloc = EventLog::allocate_logs(max_log_count);
+                let realloc = EventLog::allocate_logs(max_log_count);
+                let failure = EventLog::allocate_logs(max_log_count);
 
-        // Safety: `start_track` and is not called concurrently
-        {
-            let a = self as *const Self;
-            let b = a as *mut Self;
-            let ff = unsafe { b.as_mut().unwrap() };
+                *self.allocation_logs.logs.get() = MaybeUninit::new(alloc);
+                *self.deallocation_logs.logs.get() = MaybeUninit::new(dealloc);
+                *self.reallocation_logs.logs.get() = MaybeUninit::new(realloc);
+                *self.failure_logs.logs.get() = MaybeUninit::new(failure);
+                *self.stacks.get() = StackTable::allocate(options.max_stack_frames);
+                self.has_buffers.store(true, Ordering::SeqCst);
+            }
+            *self.options.get() = options;
+            *self.started_at.get() = Some(Instant::now());
 
-            ff.allocation_logs = MaybeUninit::new(alloc);
-            ff.deallocation_logs = MaybeUninit::new(dealloc);
-        }
+            // Reset under the lock too: a snapshot taken as the session starts
+            // must not mix the events of the previous one with the new ones
+            self.allocation_logs.reset();
+            self.deallocation_logs.reset();
+            self.reallocation_logs.reset();
+            self.failure_logs.reset();
+            self.stacks.reset();
+            self.sequence.store(0, Ordering::SeqCst);
+            self.fault_candidates.store(0, Ordering::SeqCst);
+            self.session_counters.reset();
+            let budget = options.memory_budget.map_or(usize::MAX, |(bytes, _)| bytes);
+            self.budget.store(budget, Ordering::SeqCst);
+            let budget_panics = matches!(options.memory_budget, Some((_, BudgetPolicy::Panic)));
+            self.budget_panics.store(budget_panics, Ordering::SeqCst);
+        });
 
+        self.state.store(TRACKING, Ordering::SeqCst);
         self.is_tracking.store(true, Ordering::SeqCst);
     }
 
     /// Stop recording allocations.
     pub fn stop_track(&self) {
         self.is_tracking.store(false, Ordering::SeqCst);
+        // A stream goes back to idle once its writer is done
+        if !self.streaming.load(Ordering::SeqCst) {
+            let _ = self.transition(&[TRACKING], STOPPED);
+        }
     }
 
+    /// Reserve the next slot of `logs` and fill it with the current backtrace.
+    /// Returns `None` if the event is dropped because of the overflow policy.
+    ///
+    /// # Safety
+    ///
+    /// `logs` must have been initialized by `start_track`.
     #[allow(clippy::mut_from_ref)]
-    unsafe fn get_allocation_item_mut(&self, index: usize) -> &mut LogType {
-        let allocatio_logs = unsafe { self.allocation_logs.assume_init_ref() };
-        let element = &allocatio_logs[index];
-        unsafe { (&mut *element.get() as &mut LogType) as _ }
+    unsafe fn log_event<'a>(&self, logs: &'a EventLog) -> Option<&'a mut LogEntry> {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst);
+        let timestamp = self
+            .started_at
+            .map_or(0, |started_at| started_at.elapsed().as_nanos() as u64);
+
+        let max_log_count = self.options.max_log_count;
+        if self.streaming.load(Ordering::Relaxed) {
+            let index = logs.reserve_streamed(max_log_count)?;
+            // Safety: the writer is done with the previous event in the slot
+            let log = unsafe { logs.get_mut(index % max_log_count) };
+            log.index = index;
+            return Some(self.fill_event(log, sequence, timestamp));
+        }
+
+        let global_index = logs.pointer.fetch_add(1, Ordering::SeqCst);
+        let mut index = global_index;
+        if index >= max_log_count {
+            match self.overflow_policy {
+                OverflowPolicy::Panic => {
+                    panic!("Log buffer overflow. Maximum log count ({max_log_count}) exceeded.")
+                }
+                OverflowPolicy::DropNewest => return None,
+                // A writer lapping another one on the same slot requires more than
+                // `max_log_count` concurrent events: we accept the risk.
+                OverflowPolicy::RingBuffer => index %= max_log_count,
+                OverflowPolicy::StopTracking => {
+                    self.is_tracking.store(false, Ordering::SeqCst);
+                    return None;
+                }
+            }
+        }
+
+        // Safety: index is incrementally increasing and within bounds
+        // So, we can safely get a mutable reference to the log at this index.
+        let log = unsafe { logs.get_mut(index) };
+        log.index = global_index;
+        Some(self.fill_event(log, sequence, timestamp))
     }
 
-    #[allow(clippy::mut_from_ref)]
-    unsafe fn get_deallocation_item_mut(&self, index: usize) -> &mut LogType {
-        let deallocation_logs = unsafe { self.deallocation_logs.assume_init_ref() };
-        let element = &deallocation_logs[index];
-        unsafe { (&mut *element.get() as &mut LogType) as _ }
+    /// Record the metadata shared by all the events, and the current backtrace
+    fn fill_event<'a>(
+        &self,
+        log: &'a mut LogEntry,
+        sequence: u64,
+        timestamp: u64,
+    ) -> &'a mut LogEntry {
+        // Readers taking a snapshot must not see a half-written event
+        log.committed.store(0, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        log.data.sequence = sequence;
+        log.data.timestamp = timestamp;
+        log.data.thread_id = thread::current_thread_id();
+        log.data.thread_name_len = thread::current_thread_name(&mut log.data.thread_name);
+        log.data.region = region::current();
+
+        let mut skip = self.options.skip_frames;
+        let mut depth: usize = 0;
+        let mut truncated = false;
+        let mut stack = self.stacks.builder();
+        self.options.unwinder.trace(|ip| {
+            if skip > 0 {
+                skip -= 1;
+                return true;
+            }
+            if depth == self.options.max_frame_length {
+                truncated = true;
+                return false;
+            }
+            stack.push(ip);
+            depth += 1;
+            true
+        });
+        log.data.stack = stack.finish(truncated);
+
+        log
     }
 
-    unsafe fn get_allocation_item(&self, index: usize) -> &LogType {
-        let allocatio_logs = unsafe { self.allocation_logs.assume_init_ref() };
-        let element = &allocatio_logs[index];
-        unsafe { (&*element.get() as &LogType) as _ }
+    /// Write an event with `f`, unless the session stopped or the current thread is
+    /// already recording one. The buffers and the options are not read in full,
+    /// reset, freed nor replaced before `f` returns
+    fn record<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        // Checked after registering: either `wait_for_recorders` sees this thread,
+        // or this thread sees the session stopped
+        self.recorders.fetch_add(1, Ordering::SeqCst);
+        let _done = Unlock(&self.recorders, 1);
+        if !self.is_tracking.load(Ordering::SeqCst) {
+            return None;
+        }
+        if let Some(thread_id) = self.options.thread_id
+            && thread_id != thread::current_thread_id()
+        {
+            return None;
+        }
+        without_reentrancy(f)
+    }
+
+    /// Wait for the threads still writing an event, once `is_tracking` is cleared
+    fn wait_for_recorders(&self) {
+        while self.recorders.load(Ordering::SeqCst) != 0 {
+            std::thread::yield_now();
+        }
     }
 
-    unsafe fn get_deallocation_item(&self, index: usize) -> &LogType {
-        let deallocation_logs = unsafe { self.deallocation_logs.assume_init_ref() };
-        let element = &deallocation_logs[index];
-        unsafe { (&*element.get() as &LogType) as _ }
+    /// Whether allocating `growth` more bytes brings the session over its budget
+    fn exceeds_budget(&self, growth: isize) -> bool {
+        let budget = self.budget.load(Ordering::Relaxed);
+        budget != usize::MAX
+            && self.session_counters.live_bytes().saturating_add(growth) > budget as isize
     }
 
-    unsafe fn log_alloc(&self, layout: &Layout, address: usize) {
-        let index = self.allocation_logs_pointer.fetch_add(1, Ordering::SeqCst);
-        if index >= MAX_LOG_COUNT {
-            panic!("Log buffer overflow. Maximum log count ({MAX_LOG_COUNT}) exceeded.");
+    /// Refuse an allocation of `size` bytes according to the budget policy
+    fn over_budget(&self, size: usize) -> *mut u8 {
+        if !self.budget_panics.load(Ordering::SeqCst) {
+            return std::ptr::null_mut();
         }
 
-        // Safety: index is incrementally increasing and within bounds
-        // So, we can safely get a mutable reference to the log at this index.
-        let log = unsafe { self.get_allocation_item_mut(index) };
-        log.0 = layout.size();
-
-        let mut i: usize = 0;
-        backtrace::trace(|frame| {
-            let ip: *mut c_void = frame.ip();
-            log.3[i].ip = Some(ip as usize);
-            i += 1;
-            true
+        // Allocations are needed to report the error: let them through
+        let budget = self.budget.swap(usize::MAX, Ordering::SeqCst);
+        let mut message = String::new();
+        // Don't record the allocations made to build the message
+        without_reentrancy(|| {
+            message = format!(
+                "memory budget of {budget} bytes exceeded: {} bytes live, {size} bytes requested\n{}",
+                self.session_counters.live_bytes(),
+                std::backtrace::Backtrace::force_capture()
+            );
         });
-        log.1 = i;
-        log.2 = address;
+        panic!("{message}");
     }
 
-    unsafe fn log_dealloc(&self, layout: &Layout, address: usize) {
-        let index = self
-            .deallocation_logs_pointer
-            .fetch_add(1, Ordering::SeqCst);
-        if index >= MAX_LOG_COUNT {
-            panic!("Log buffer overflow. Maximum log count ({MAX_LOG_COUNT}) exceeded.");
+    /// Whether the allocation of `size` bytes has to fail on purpose, recording the
+    /// failure. `previous_address` is the block a reallocation tries to move
+    fn inject_fault(&self, size: usize, previous_address: usize) -> bool {
+        // The options are read as a recorder: no session replaces them meanwhile
+        let failed = self.record(|| {
+            let Some(fault_injection) = self.options.fault_injection else {
+                return false;
+            };
+            let index = self.fault_candidates.fetch_add(1, Ordering::Relaxed);
+            let failed = fault_injection.should_fail(index, size);
+            if failed {
+                unsafe { self.log_failure(size, previous_address) };
+            }
+            failed
+        });
+        failed == Some(true)
+    }
+
+    /// Whether an event of `size` bytes has to be recorded, and with which weight.
+    fn sample(&self, size: usize) -> Option<f64> {
+        match self.sampling_rate {
+            None => Some(1.0),
+            Some(sampling_rate) => sampling::sample(size, sampling_rate),
         }
+    }
 
-        // Safety: index is incrementally increasing and within bounds
-        // So, we can safely get a mutable reference to the log at this index.
-        let log = unsafe { self.get_deallocation_item_mut(index) };
-        log.0 = layout.size();
-
-        let mut i: usize = 0;
-        backtrace::trace(|frame| {
-            let ip: *mut c_void = frame.ip();
-            log.3[i].ip = Some(ip as usize);
-            i += 1;
-            true
-        });
-        log.1 = i;
-        log.2 = address;
+    unsafe fn log_alloc(&self, layout: &Layout, address: usize) {
+        let Some(weight) = self.sample(layout.size()) else {
+            return;
+        };
+        let Some(log) = (unsafe { self.log_event(&self.allocation_logs) }) else {
+            return;
+        };
+        log.data.size = layout.size();
+        log.data.address = address;
+        log.data.weight = weight;
+        log.commit();
+    }
+
+    unsafe fn log_dealloc(&self, layout: &Layout, address: usize) {
+        let Some(weight) = self.sample(layout.size()) else {
+            return;
+        };
+        let Some(log) = (unsafe { self.log_event(&self.deallocation_logs) }) else {
+            return;
+        };
+        log.data.size = layout.size();
+        log.data.address = address;
+        log.data.weight = weight;
+        log.commit();
+    }
+
+    /// Record an allocation failed on purpose. Never sampled.
+    /// `previous_address` is the block a reallocation tried to move, 0 for an allocation.
+    unsafe fn log_failure(&self, size: usize, previous_address: usize) {
+        let Some(log) = (unsafe { self.log_event(&self.failure_logs) }) else {
+            return;
+        };
+        log.data.weight = 1.0;
+        log.data.size = size;
+        log.data.previous_address = previous_address;
+        log.data.address = 0;
+        log.commit();
+    }
+
+    unsafe fn log_realloc(
+        &self,
+        layout: &Layout,
+        new_size: usize,
+        previous_address: usize,
+        address: usize,
+    ) {
+        let Some(weight) = self.sample(new_size) else {
+            return;
+        };
+        let Some(log) = (unsafe { self.log_event(&self.reallocation_logs) }) else {
+            return;
+        };
+        log.data.weight = weight;
+        log.data.previous_size = layout.size();
+        log.data.size = new_size;
+        log.data.previous_address = previous_address;
+        log.data.address = address;
+        log.commit();
     }
 
     /// Calculate the statistics of the allocations.
     ///
+    /// Prefer [`RalloAllocator::collect`] or [`TrackingGuard::finish`], which detect misuses.
+    ///
     /// # Safety
     ///
     /// It is the caller's responsibility to ensure that the allocator is not tracking
@@ -220,123 +1371,750 @@ impl<const MAX_FRAME_LENGTH: usize, const MAX_LOG_COUNT: usize>
     /// Don't call this function concurrently
     ///
     pub unsafe fn calculate_stats(&self) -> Stats {
-        let mut stats = Stats {
-            allocations: VecDeque::new(),
-            deallocations: VecDeque::new(),
-        };
+        unsafe { self.take_capture() }.into_stats()
+    }
 
-        let index = self.allocation_logs_pointer.load(Ordering::SeqCst);
-        for i in 0..index {
-            let log = unsafe { self.get_allocation_item(i) };
+    /// The events of the stopped session, unsymbolized and without the modules.
+    /// Resets the buffers for the next session.
+    ///
+    /// # Safety
+    ///
+    /// Same as `calculate_stats`.
+    unsafe fn take_capture(&self) -> Capture {
+        self.wait_for_recorders();
+        let is_ring = self.overflow_policy == OverflowPolicy::RingBuffer;
+        let max_log_count = self.options.max_log_count;
+        let logs = [
+            (&self.allocation_logs, RawEventKind::Allocation),
+            (&self.deallocation_logs, RawEventKind::Deallocation),
+            (&self.reallocation_logs, RawEventKind::Reallocation),
+            (&self.failure_logs, RawEventKind::FailedAllocation),
+        ];
 
-            let address = log.2;
+        let mut events = Vec::new();
+        for (log, kind) in logs {
+            // Only the committed events: the ones interrupted by a panic are left out
+            unsafe { log.snapshot(kind, is_ring, &self.stacks, &mut events) };
+        }
+        events.sort_by_key(|event| event.sequence);
 
-            let mut allocation = Allocation {
-                allocation_size: log.0,
-                deallocation_size: 0,
-                address,
-                stack: VecDeque::new(),
-            };
+        let dropped_events = logs.iter().map(|(log, _)| log.dropped(max_log_count)).sum();
 
-            let stack_size = log.1;
-            for j in 0..stack_size {
-                let frame = &log.3[j];
-                let ip = frame.ip.unwrap() as *mut c_void;
-
-                let mut filename: Option<std::path::PathBuf> = None;
-                let mut colno: Option<u32> = None;
-                let mut lineno: Option<u32> = None;
-                let mut fn_address: Option<*mut c_void> = None;
-                let mut fn_name: Option<String> = None;
-                backtrace::resolve(ip, |s| {
-                    filename = s.filename().map(|f| f.to_owned());
-                    colno = s.colno();
-                    lineno = s.lineno();
-                    fn_address = s.addr();
-                    fn_name = s.name().and_then(|s| s.as_str()).map(|s| s.to_string());
-                });
-                allocation.stack.push_front(FrameInfo {
-                    filename,
-                    colno,
-                    lineno,
-                    fn_address,
-                    fn_name,
-                });
-            }
+        for (log, _) in logs {
+            log.pointer.store(0, Ordering::SeqCst);
+        }
+        self.state.store(IDLE, Ordering::SeqCst);
 
-            stats.allocations.push_front(allocation);
+        Capture {
+            modules: Vec::new(),
+            events,
+            dropped_events,
+            sampling_rate: self.sampling_rate,
+            counters: self.session_counters.snapshot(),
         }
+    }
+}
 
-        let index = self.deallocation_logs_pointer.load(Ordering::SeqCst);
-        for i in 0..index {
-            let log = unsafe { self.get_deallocation_item(i) };
+/// A running tracking session, created by [`RalloAllocator::start`].
+///
+/// Dropping it stops the session: its stats can then be collected
+/// with [`RalloAllocator::collect`].
+pub struct TrackingGuard<'a, A: GlobalAlloc = System> {
+    allocator: &'a RalloAllocator<A>,
+}
 
-            let address = log.2;
-            let mut deallocation = Allocation {
-                allocation_size: 0,
-                deallocation_size: log.0,
-                address,
-                stack: VecDeque::new(),
-            };
+impl<A: GlobalAlloc> TrackingGuard<'_, A> {
+    /// Stop the session and calculate its statistics.
+    pub fn finish(self) -> Result<Stats, TrackingError> {
+        let allocator = self.allocator;
+        // Don't run `drop`: it could stop a session started after the collection
+        std::mem::forget(self);
 
-            let stack_size = log.1;
-            for j in 0..stack_size {
-                let frame = &log.3[j];
-                let ip = frame.ip.unwrap() as *mut c_void;
-
-                let mut filename: Option<std::path::PathBuf> = None;
-                let mut colno: Option<u32> = None;
-                let mut lineno: Option<u32> = None;
-                let mut fn_address: Option<*mut c_void> = None;
-                let mut fn_name: Option<String> = None;
-                backtrace::resolve(ip, |s| {
-                    filename = s.filename().map(|f| f.to_owned());
-                    colno = s.colno();
-                    lineno = s.lineno();
-                    fn_address = s.addr();
-                    fn_name = s.name().and_then(|s| s.as_str()).map(|s| s.to_string());
-                });
-                deallocation.stack.push_front(FrameInfo {
-                    filename,
-                    colno,
-                    lineno,
-                    fn_address,
-                    fn_name,
-                });
-            }
+        allocator.stop_track();
+        allocator.collect()
+    }
+
+    /// Stop the session and return its events unsymbolized, see
+    /// [`RalloAllocator::collect_capture`].
+    pub fn finish_capture(self) -> Result<Capture, TrackingError> {
+        let allocator = self.allocator;
+        std::mem::forget(self);
+
+        allocator.stop_track();
+        allocator.collect_capture()
+    }
+}
+
+impl<A: GlobalAlloc> Drop for TrackingGuard<'_, A> {
+    fn drop(&mut self) {
+        self.allocator.stop_track();
+    }
+}
 
-            stats.deallocations.push_front(deallocation);
+/// A running streaming session, created by [`RalloAllocator::stream`].
+///
+/// Dropping it stops the session, waiting for the events to be written.
+pub struct StreamingGuard<W, A: GlobalAlloc + 'static = System> {
+    allocator: &'static RalloAllocator<A>,
+    stop: Arc<AtomicBool>,
+    writer: Option<JoinHandle<io::Result<W>>>,
+}
+
+impl<W, A: GlobalAlloc> StreamingGuard<W, A> {
+    /// Stop the session, write the remaining events and return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<W> {
+        let allocator = self.allocator;
+        allocator.stop_track();
+        self.stop.store(true, Ordering::SeqCst);
+        let result = match self.writer.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            _ => Err(io::Error::other("the rallo writer thread panicked")),
+        };
+
+        allocator.streaming.store(false, Ordering::SeqCst);
+        allocator.state.store(IDLE, Ordering::SeqCst);
+        result
+    }
+}
+
+impl<W, A: GlobalAlloc> Drop for StreamingGuard<W, A> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.stop();
         }
+    }
+}
 
-        self.allocation_logs_pointer.store(0, Ordering::SeqCst);
-        self.deallocation_logs_pointer.store(0, Ordering::SeqCst);
+/// Misuses of the tracking API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackingError {
+    /// A session is already running
+    AlreadyTracking,
+    /// The stats can't be collected while the session is running
+    StillTracking,
+    /// Another thread is collecting the stats
+    Collecting,
+    /// No session was started since the last collection
+    NotStarted,
+}
 
-        stats
+impl Display for TrackingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            TrackingError::AlreadyTracking => "a tracking session is already running",
+            TrackingError::StillTracking => "the tracking session is still running",
+            TrackingError::Collecting => "the stats are being collected by another thread",
+            TrackingError::NotStarted => "no tracking session to collect",
+        };
+        f.write_str(message)
     }
 }
 
-unsafe impl<const MAX_FRAME_LENGTH: usize, const MAX_LOG_COUNT: usize> GlobalAlloc
-    for RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT>
-{
+impl std::error::Error for TrackingError {}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for RalloAllocator<A> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let is_tracked = self.is_tracking.load(Ordering::SeqCst) && !is_recording();
+        if is_tracked && self.exceeds_budget(layout.size() as isize) {
+            return self.over_budget(layout.size());
+        }
+        if is_tracked && self.inject_fault(layout.size(), 0) {
+            return std::ptr::null_mut();
+        }
+
         let ptr = unsafe { self.alloc.alloc(layout) };
+        if !ptr.is_null() {
+            self.counters.on_alloc(layout.size());
+        }
 
         // Don't track allocations if not enabled
         if self.is_tracking.load(Ordering::SeqCst) {
+            // Rallo's own allocations don't count in the session
+            if !ptr.is_null() && !is_recording() {
+                self.session_counters.on_alloc(layout.size());
+            }
             let address = ptr as usize;
-            unsafe { self.log_alloc(&layout, address) };
+            self.record(|| unsafe { self.log_alloc(&layout, address) });
         }
 
         ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.counters.on_dealloc(layout.size());
+
         // Don't track allocations if not enabled
         if self.is_tracking.load(Ordering::SeqCst) {
+            if !is_recording() {
+                self.session_counters.on_dealloc(layout.size());
+            }
             let address = ptr as usize;
-            unsafe { self.log_dealloc(&layout, address) };
+            self.record(|| unsafe { self.log_dealloc(&layout, address) });
         }
 
         unsafe { self.alloc.dealloc(ptr, layout) }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let growth = new_size as isize - layout.size() as isize;
+        let is_tracked = self.is_tracking.load(Ordering::SeqCst) && !is_recording();
+        if is_tracked && growth > 0 && self.exceeds_budget(growth) {
+            return self.over_budget(new_size);
+        }
+        if is_tracked && self.inject_fault(new_size, ptr as usize) {
+            return std::ptr::null_mut();
+        }
+
+        let new_ptr = unsafe { self.alloc.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            self.counters.on_realloc(layout.size(), new_size);
+        }
+
+        // On failure the original block is left untouched: nothing to record
+        if !new_ptr.is_null() && self.is_tracking.load(Ordering::SeqCst) {
+            if !is_recording() {
+                self.session_counters.on_realloc(layout.size(), new_size);
+            }
+            self.record(|| unsafe {
+                self.log_realloc(&layout, new_size, ptr as usize, new_ptr as usize)
+            });
+        }
+
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(allocator: &RalloAllocator, max_log_count: usize, sizes: &[usize]) -> Stats {
+        let layouts: Vec<_> = sizes
+            .iter()
+            .map(|size| Layout::from_size_align(*size, 1).unwrap())
+            .collect();
+        let mut pointers = Vec::with_capacity(layouts.len());
+
+        let options = TrackOptions::new().max_log_count(max_log_count);
+        let guard = allocator.start_with(options).unwrap();
+        for layout in &layouts {
+            pointers.push(unsafe { allocator.alloc(*layout) });
+        }
+        drop(guard);
+
+        for (ptr, layout) in pointers.into_iter().zip(layouts) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+
+        allocator.collect().unwrap()
+    }
+
+    fn allocation_sizes(stats: &Stats) -> Vec<usize> {
+        stats
+            .allocations
+            .iter()
+            .map(|allocation| allocation.allocation_size)
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow_drop_newest() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::DropNewest);
+        let stats = record(&allocator, 4, &[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(allocation_sizes(&stats), vec![4, 3, 2, 1]);
+        assert_eq!(stats.dropped_events, 2);
+    }
+
+    #[test]
+    fn test_overflow_ring_buffer() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::RingBuffer);
+        let stats = record(&allocator, 4, &[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(allocation_sizes(&stats), vec![6, 5, 4, 3]);
+        assert_eq!(stats.dropped_events, 2);
+    }
+
+    #[test]
+    fn test_overflow_stop_tracking() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::StopTracking);
+        let stats = record(&allocator, 4, &[1, 2, 3, 4, 5, 6]);
+
+        assert_eq!(allocation_sizes(&stats), vec![4, 3, 2, 1]);
+        assert_eq!(stats.dropped_events, 1);
+        assert!(!allocator.is_tracking.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let allocator = RalloAllocator::new().sampling_rate(1024);
+        let stats = record(&allocator, 16, &[1024 * 1024, 1024 * 1024]);
+
+        assert_eq!(stats.sampling_rate, Some(1024));
+        // Events much larger than the sampling rate are always sampled, with no extra weight
+        assert_eq!(allocation_sizes(&stats), vec![1024 * 1024, 1024 * 1024]);
+        for allocation in &stats.allocations {
+            assert_eq!(allocation.estimated_count(), 1);
+            assert_eq!(allocation.estimated_allocation_size(), 1024 * 1024);
+        }
+        // The frees are sampled independently of their allocations
+        assert!(stats.into_peak().is_err());
+    }
+
+    #[test]
+    fn test_tracking_misuses() {
+        let allocator = RalloAllocator::new();
+
+        assert_eq!(allocator.collect().unwrap_err(), TrackingError::NotStarted);
+
+        let guard = allocator.start().unwrap();
+        assert_eq!(
+            allocator.start().err().unwrap(),
+            TrackingError::AlreadyTracking
+        );
+        assert_eq!(
+            allocator.collect().unwrap_err(),
+            TrackingError::StillTracking
+        );
+        assert_eq!(
+            allocator.track(|| {}).unwrap_err(),
+            TrackingError::AlreadyTracking
+        );
+        guard.finish().unwrap();
+
+        assert_eq!(allocator.collect().unwrap_err(), TrackingError::NotStarted);
+        allocator.track(|| {}).unwrap();
+    }
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let allocator = RalloAllocator::new();
+        let logs_address = |allocator: &RalloAllocator| {
+            unsafe { allocator.allocation_logs.logs.assume_init() }.as_ptr()
+        };
+
+        let stats = record(&allocator, 16, &[1, 2]);
+        assert_eq!(allocation_sizes(&stats), vec![2, 1]);
+        let first = logs_address(&allocator);
+
+        let stats = record(&allocator, 16, &[3]);
+        assert_eq!(allocation_sizes(&stats), vec![3]);
+        assert_eq!(logs_address(&allocator), first);
+
+        let guard = allocator.start().unwrap();
+        assert_eq!(
+            allocator.release_buffers().unwrap_err(),
+            TrackingError::StillTracking
+        );
+        guard.finish().unwrap();
+
+        allocator.release_buffers().unwrap();
+        assert!(!allocator.has_buffers.load(Ordering::SeqCst));
+
+        let stats = record(&allocator, 16, &[4]);
+        assert_eq!(allocation_sizes(&stats), vec![4]);
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_inner_allocator() {
+        #[derive(Default)]
+        struct Counting {
+            allocated: AtomicUsize,
+            freed: AtomicUsize,
+        }
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.allocated.fetch_add(layout.size(), Ordering::SeqCst);
+                unsafe { System.alloc(layout) }
+            }
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.freed.fetch_add(layout.size(), Ordering::SeqCst);
+                unsafe { System.dealloc(ptr, layout) }
+            }
+        }
+
+        let allocator = RalloAllocator::<Counting>::default();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let guard = allocator.start().unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        // `GlobalAlloc::realloc` falls back on `alloc` and `dealloc` of the inner allocator
+        let ptr = unsafe { allocator.realloc(ptr, layout, 128) };
+        let stats = guard.finish().unwrap();
+        unsafe { allocator.dealloc(ptr, Layout::from_size_align(128, 8).unwrap()) };
+
+        assert_eq!(allocator.alloc.allocated.load(Ordering::SeqCst), 64 + 128);
+        assert_eq!(allocator.alloc.freed.load(Ordering::SeqCst), 64 + 128);
+        assert_eq!(allocation_sizes(&stats), vec![64]);
+        assert_eq!(stats.reallocations[0].allocation_size, 128);
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_counters() {
+        let allocator = RalloAllocator::new();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        // Counted whether tracking or not
+        let a = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.realloc(b, layout, 256) };
+        unsafe { allocator.dealloc(a, layout) };
+        let b = unsafe { allocator.realloc(b, Layout::from_size_align(256, 8).unwrap(), 32) };
+
+        assert_eq!(
+            allocator.counters(),
+            Counters {
+                live_bytes: 32,
+                peak_live_bytes: 64 + 256,
+                total_allocations: 2,
+                total_deallocations: 1,
+                total_reallocations: 2,
+                total_bytes_allocated: 64 + 64 + 256 + 32,
+                total_bytes_freed: 64 + 64 + 256,
+            }
+        );
+
+        unsafe { allocator.dealloc(b, Layout::from_size_align(32, 8).unwrap()) };
+        assert_eq!(allocator.counters().live_bytes, 0);
+    }
+
+    #[test]
+    fn test_peak() {
+        let allocator = RalloAllocator::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+        // Allocated before the session: the replay doesn't know it
+        let before = unsafe { allocator.alloc(layout(1000)) };
+
+        let guard = allocator.start().unwrap();
+        let a = unsafe { allocator.alloc(layout(100)) };
+        let b = unsafe { allocator.alloc(layout(200)) };
+        unsafe { allocator.dealloc(a, layout(100)) };
+        let c = unsafe { allocator.alloc(layout(50)) };
+        let b = unsafe { allocator.realloc(b, layout(200), 400) };
+        unsafe { allocator.dealloc(b, layout(400)) };
+        unsafe { allocator.dealloc(c, layout(50)) };
+        unsafe { allocator.dealloc(before, layout(1000)) };
+        let stats = guard.finish().unwrap();
+
+        assert_eq!(stats.counters.peak_live_bytes, 450);
+        // Net amount: the session freed more than it allocated
+        assert_eq!(stats.counters.live_bytes, 0);
+        assert_eq!(
+            stats.counters.total_bytes_freed,
+            100 + 200 + 400 + 50 + 1000
+        );
+
+        let peak = stats.into_peak().unwrap();
+        let peak_info = peak.peak.unwrap();
+        assert_eq!(peak_info.live_bytes, 450);
+        // The reallocation reached the peak
+        assert_eq!(peak_info.sequence, 4);
+        assert_eq!(allocation_sizes(&peak), vec![400, 50]);
+        assert!(peak.deallocations.is_empty());
+        assert!(peak.reallocations.is_empty());
+
+        let tree = peak.into_tree().unwrap();
+        assert_eq!(tree.allocation, 450);
+        assert_eq!(tree.deallocation, 0);
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let allocator = RalloAllocator::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+        let options = TrackOptions::new().memory_budget(1000, BudgetPolicy::ReturnNull);
+        let guard = allocator.start_with(options).unwrap();
+        let a = unsafe { allocator.alloc(layout(600)) };
+        assert!(!a.is_null());
+        assert!(unsafe { allocator.alloc(layout(600)) }.is_null());
+        let a = unsafe { allocator.realloc(a, layout(600), 900) };
+        assert!(!a.is_null());
+        assert!(unsafe { allocator.realloc(a, layout(900), 1200) }.is_null());
+        unsafe { allocator.dealloc(a, layout(900)) };
+        let b = unsafe { allocator.alloc(layout(1000)) };
+        assert!(!b.is_null());
+        let stats = guard.finish().unwrap();
+        unsafe { allocator.dealloc(b, layout(1000)) };
+
+        // Refused allocations are not recorded
+        assert_eq!(allocation_sizes(&stats), vec![1000, 600]);
+        assert_eq!(stats.counters.peak_live_bytes, 1000);
+
+        // The budget only applies to its session
+        let guard = allocator.start().unwrap();
+        let c = unsafe { allocator.alloc(layout(2000)) };
+        assert!(!c.is_null());
+        guard.finish().unwrap();
+        unsafe { allocator.dealloc(c, layout(2000)) };
+
+        let options = TrackOptions::new().memory_budget(1000, BudgetPolicy::Panic);
+        let guard = allocator.start_with(options).unwrap();
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+            allocator.alloc(layout(2000))
+        }))
+        .unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("memory budget of 1000 bytes exceeded"));
+        // The budget is not enforced anymore
+        let d = unsafe { allocator.alloc(layout(2000)) };
+        assert!(!d.is_null());
+        guard.finish().unwrap();
+        unsafe { allocator.dealloc(d, layout(2000)) };
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let allocator = RalloAllocator::new();
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+        let options = TrackOptions::new().fail_allocations(FaultInjection::Nth(1));
+        let guard = allocator.start_with(options).unwrap();
+        let a = unsafe { allocator.alloc(layout(8)) };
+        assert!(!a.is_null());
+        // The reallocation is the second call: `a` is left untouched
+        assert!(unsafe { allocator.realloc(a, layout(8), 16) }.is_null());
+        let b = unsafe { allocator.alloc(layout(32)) };
+        assert!(!b.is_null());
+        let stats = guard.finish().unwrap();
+        unsafe { allocator.dealloc(a, layout(8)) };
+        unsafe { allocator.dealloc(b, layout(32)) };
+
+        assert_eq!(allocation_sizes(&stats), vec![32, 8]);
+        assert!(stats.reallocations.is_empty());
+        assert_eq!(stats.failed_allocations.len(), 1);
+        let failure = &stats.failed_allocations[0];
+        assert_eq!(failure.allocation_size, 16);
+        assert_eq!(failure.previous_address, Some(a as usize));
+        assert!(!failure.stack.is_empty());
+
+        let options = TrackOptions::new().fail_allocations(FaultInjection::Size(|size| size == 64));
+        let guard = allocator.start_with(options).unwrap();
+        assert!(unsafe { allocator.alloc(layout(64)) }.is_null());
+        assert!(unsafe { allocator.alloc(layout(64)) }.is_null());
+        let c = unsafe { allocator.alloc(layout(65)) };
+        let stats = guard.finish().unwrap();
+        unsafe { allocator.dealloc(c, layout(65)) };
+
+        assert_eq!(stats.failed_allocations.len(), 2);
+        assert_eq!(stats.failed_allocations[0].previous_address, None);
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::RingBuffer);
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        assert_eq!(allocator.snapshot().unwrap_err(), TrackingError::NotStarted);
+
+        let guard = allocator
+            .start_with(TrackOptions::new().max_log_count(4))
+            .unwrap();
+        let a = unsafe { allocator.alloc(layout(1)) };
+        let b = unsafe { allocator.alloc(layout(2)) };
+        let snapshot = allocator.snapshot().unwrap();
+        assert_eq!(allocation_sizes(&snapshot), vec![2, 1]);
+        assert_eq!(snapshot.counters.live_bytes, 3);
+
+        // The session goes on, keeping the events of the snapshot
+        unsafe { allocator.dealloc(a, layout(1)) };
+        let snapshot = allocator.snapshot().unwrap();
+        assert_eq!(allocation_sizes(&snapshot), vec![2, 1]);
+        assert_eq!(snapshot.deallocations.len(), 1);
+
+        // Only the events still in the ring
+        let pointers: Vec<_> = (3..=5)
+            .map(|size| unsafe { allocator.alloc(layout(size)) })
+            .collect();
+        let snapshot = allocator.snapshot().unwrap();
+        assert_eq!(allocation_sizes(&snapshot), vec![5, 4, 3, 2]);
+        assert_eq!(snapshot.dropped_events, 1);
+        assert!(snapshot.allocations.iter().all(|a| !a.stack.is_empty()));
+
+        let stats = guard.finish().unwrap();
+        assert_eq!(allocation_sizes(&stats), vec![5, 4, 3, 2]);
+        assert_eq!(allocator.snapshot().unwrap_err(), TrackingError::NotStarted);
+
+        unsafe { allocator.dealloc(b, layout(2)) };
+        for (ptr, size) in pointers.into_iter().zip(3..) {
+            unsafe { allocator.dealloc(ptr, layout(size)) };
+        }
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_while_recording() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::RingBuffer);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let stop = AtomicBool::new(false);
+
+        let guard = allocator
+            .start_with(TrackOptions::new().max_log_count(64))
+            .unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !stop.load(Ordering::SeqCst) {
+                        let ptr = unsafe { allocator.alloc(layout) };
+                        unsafe { allocator.dealloc(ptr, layout) };
+                    }
+                });
+            }
+            for _ in 0..20 {
+                let snapshot = allocator.snapshot().unwrap();
+                // Events being overwritten are left out, not torn
+                assert!(snapshot.allocations.len() <= 64);
+                assert!(allocation_sizes(&snapshot).iter().all(|size| *size == 24));
+                assert!(
+                    snapshot
+                        .deallocations
+                        .iter()
+                        .all(|d| d.deallocation_size == 24)
+                );
+            }
+            stop.store(true, Ordering::SeqCst);
+        });
+        guard.finish().unwrap();
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_buffers_are_sized_per_session() {
+        let allocator = RalloAllocator::with_overflow_policy(OverflowPolicy::DropNewest);
+        let logs_len = |allocator: &RalloAllocator| {
+            unsafe { allocator.allocation_logs.logs.assume_init() }.len()
+        };
+
+        let stats = record(&allocator, 2, &[1, 2, 3]);
+        assert_eq!(allocation_sizes(&stats), vec![2, 1]);
+        assert_eq!(stats.dropped_events, 1);
+        assert_eq!(logs_len(&allocator), 2);
+
+        let stats = record(&allocator, 8, &[1, 2, 3]);
+        assert_eq!(allocation_sizes(&stats), vec![3, 2, 1]);
+        assert_eq!(stats.dropped_events, 0);
+        assert_eq!(logs_len(&allocator), 8);
+
+        let options = TrackOptions::new().max_frame_length(4);
+        let stats = allocator
+            .track_with(options, || drop(Vec::<u8>::with_capacity(8)))
+            .unwrap();
+        assert!(stats.allocations.iter().all(|a| a.stack.len() <= 4));
+        assert!(stats.allocations.iter().all(|a| a.truncated));
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_skip_frames() {
+        let allocator = RalloAllocator::new();
+        let layout = Layout::from_size_align(8, 1).unwrap();
+        let capture = |skip_frames| {
+            let options = TrackOptions::new().skip_frames(skip_frames);
+            let guard = allocator.start_with(options).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            let capture = guard.finish_capture().unwrap();
+            unsafe { allocator.dealloc(ptr, layout) };
+            capture
+        };
+        let [full, skipped] = [0, 3].map(capture);
+
+        let (full, skipped) = (&full.events[0], &skipped.events[0]);
+        assert_eq!(skipped.frames, full.frames[3..]);
+        assert!(!full.truncated && !skipped.truncated);
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_frame_pointers_unwinder() {
+        let allocator = RalloAllocator::new();
+        let layout = Layout::from_size_align(8, 1).unwrap();
+        let options = TrackOptions::new().unwinder(Unwinder::FramePointers);
+        let guard = allocator.start_with(options).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        let capture = guard.finish_capture().unwrap();
+        unsafe { allocator.dealloc(ptr, layout) };
+
+        // At least the frames of the standard library, built with frame pointers
+        assert_eq!(capture.events.len(), 1);
+        assert!(!capture.events[0].frames.is_empty());
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_stacks_are_interned() {
+        let allocator = RalloAllocator::new();
+        let layout = Layout::from_size_align(8, 1).unwrap();
+        let session = |options| {
+            let guard = allocator.start_with(options).unwrap();
+            for _ in 0..100 {
+                let ptr = unsafe { allocator.alloc(layout) };
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+            let used = allocator.stacks.used();
+            (guard.finish_capture().unwrap(), used)
+        };
+
+        let (capture, used) = session(TrackOptions::new());
+        assert_eq!(capture.events.len(), 200);
+        let (allocations, deallocations): (Vec<_>, Vec<_>) = capture
+            .events
+            .iter()
+            .partition(|event| event.kind == RawEventKind::Allocation);
+        // Two distinct stacks, each stored once
+        for events in [&allocations, &deallocations] {
+            assert!(events.iter().all(|event| event.frames == events[0].frames));
+        }
+        let frames = allocations[0].frames.len() + deallocations[0].frames.len();
+        assert!(used < 2 * frames, "{used} words for {frames} frames");
+
+        // Without room for them, the stacks are recorded empty
+        let (capture, _) = session(TrackOptions::new().max_stack_frames(4));
+        assert_eq!(capture.events.len(), 200);
+        assert!(
+            capture
+                .events
+                .iter()
+                .all(|event| event.frames.is_empty() && event.truncated)
+        );
+        allocator.release_buffers().unwrap();
+    }
+
+    #[test]
+    fn test_collect_waits_for_recorders() {
+        let allocator = RalloAllocator::new();
+        let layout = Layout::from_size_align(8, 1).unwrap();
+        let guard = allocator.start().unwrap();
+        let a = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.alloc(layout) };
+        // An event whose recording was interrupted is left out
+        unsafe { allocator.allocation_logs.get(0) }
+            .committed
+            .store(0, Ordering::SeqCst);
+        // Another thread is still writing an event
+        allocator.recorders.fetch_add(1, Ordering::SeqCst);
+        drop(guard);
+
+        let capture = std::thread::scope(|scope| {
+            let collect = scope.spawn(|| allocator.collect_capture().unwrap());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!collect.is_finished());
+            allocator.recorders.fetch_sub(1, Ordering::SeqCst);
+            collect.join().unwrap()
+        });
+        assert_eq!(capture.events.len(), 1);
+        assert_eq!(capture.events[0].address, b as usize);
+
+        unsafe { allocator.dealloc(a, layout) };
+        unsafe { allocator.dealloc(b, layout) };
+        allocator.release_buffers().unwrap();
+    }
 }
@@ -0,0 +1,156 @@
+use std::{
+    alloc::GlobalAlloc,
+    fmt::Write,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    alloc::{RalloAllocator, TrackOptions},
+    stats::{Allocation, Stats},
+};
+
+/// Limits checked by [`crate::assert_allocs!`]: `None` means no limit.
+///
+/// Reallocations count as allocations of their new size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocLimits {
+    /// Maximum number of allocations
+    pub max_count: Option<usize>,
+    /// Maximum number of bytes allocated
+    pub max_bytes: Option<usize>,
+}
+
+impl Stats {
+    /// Check the allocations against `limits`.
+    ///
+    /// On failure, returns a report listing the call sites which allocated,
+    /// the largest first.
+    pub fn check_limits(&self, limits: &AllocLimits) -> Result<(), String> {
+        let events: Vec<&Allocation> = self.allocations.iter().chain(&self.reallocations).collect();
+        let count: usize = events.iter().map(|event| event.estimated_count()).sum();
+        let bytes: usize = events
+            .iter()
+            .map(|event| event.estimated_allocation_size())
+            .sum();
+
+        let count_exceeded = limits.max_count.is_some_and(|max| count > max);
+        let bytes_exceeded = limits.max_bytes.is_some_and(|max| bytes > max);
+        if !count_exceeded && !bytes_exceeded {
+            return Ok(());
+        }
+
+        let mut report = String::from("allocation limits exceeded\n");
+        if let Some(max) = limits.max_count {
+            let _ = writeln!(report, "  allocations: expected at most {max}, got {count}");
+        }
+        if let Some(max) = limits.max_bytes {
+            let _ = writeln!(report, "  bytes:       expected at most {max}, got {bytes}");
+        }
+        if self.sampling_rate.is_some() {
+            report.push_str("  (estimated: the session was sampled)\n");
+        }
+
+        report.push_str("call sites:\n");
+        for site in self.call_sites() {
+            let _ = writeln!(
+                report,
+                "  {} allocation(s), {} bytes",
+                site.count, site.bytes
+            );
+            for frame in site.frames {
+                let _ = writeln!(report, "      at {frame}");
+            }
+        }
+
+        Err(report)
+    }
+}
+
+/// Held by the assertions while they track: the tests run in parallel, but only
+/// one session at a time can run
+static ASSERTIONS: Mutex<()> = Mutex::new(());
+
+/// Run `f` and panic with a report if the current thread allocated more than `limits`.
+/// Used by [`crate::assert_allocs!`].
+#[doc(hidden)]
+pub fn __assert_allocs<A: GlobalAlloc, F: FnOnce()>(
+    allocator: &RalloAllocator<A>,
+    limits: AllocLimits,
+    f: F,
+) {
+    // Other threads, like the ones of other tests, are not recorded: they
+    // neither count nor fill the buffers
+    let options = TrackOptions::new().current_thread_only();
+    let stats = {
+        // Poisoned by the assertions which failed while holding it: nothing to recover
+        let _lock = ASSERTIONS.lock().unwrap_or_else(PoisonError::into_inner);
+        allocator.track_with(options, f)
+    }
+    .unwrap_or_else(|e| panic!("can't check the allocations: {e}"));
+    if let Err(report) = stats.check_limits(&limits) {
+        panic!("{report}");
+    }
+}
+
+/// Assert that a closure allocates at most `max_count` times and/or `max_bytes` bytes,
+/// on the current thread. On failure, panics listing the call sites which allocated.
+///
+/// The first argument is the [`RalloAllocator`] registered as global allocator.
+/// The assertions of parallel tests take turns, but they panic if another kind
+/// of session ([`RalloAllocator::track`], [`RalloAllocator::start`],
+/// [`RalloAllocator::stream`]...) is running: keep those in a test binary of their own.
+///
+/// ```rust
+/// use rallo::{assert_allocs, RalloAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+///
+/// assert_allocs!(ALLOCATOR, max_count = 1, max_bytes = 1024, || {
+///     let _ = Vec::<u8>::with_capacity(1024);
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_allocs {
+    // Take the `name = value` limits one at a time: a single repetition followed
+    // by the closure would be ambiguous
+    (@limits $allocator:expr, [$($limits:tt)*] $limit:ident = $value:expr, $($rest:tt)+) => {
+        $crate::assert_allocs!(
+            @limits $allocator,
+            [$($limits)* $limit: ::std::option::Option::Some($value),]
+            $($rest)+
+        )
+    };
+    (@limits $allocator:expr, [$($limits:tt)*] $f:expr $(,)?) => {{
+        #[allow(clippy::needless_update)]
+        let limits = $crate::AllocLimits {
+            $($limits)*
+            ..::std::default::Default::default()
+        };
+        $crate::__assert_allocs(&$allocator, limits, $f)
+    }};
+    ($allocator:expr, $($rest:tt)+) => {
+        $crate::assert_allocs!(@limits $allocator, [] $($rest)+)
+    };
+}
+
+/// Assert that a closure doesn't allocate on the current thread.
+/// On failure, panics listing the call sites which allocated.
+///
+/// ```rust
+/// use rallo::{assert_no_alloc, RalloAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+///
+/// let numbers = [1, 2, 3];
+/// assert_no_alloc!(ALLOCATOR, || {
+///     let _: u32 = numbers.iter().sum();
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_no_alloc {
+    ($allocator:expr, $f:expr $(,)?) => {
+        $crate::assert_allocs!($allocator, max_count = 0, $f)
+    };
+}
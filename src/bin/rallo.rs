@@ -0,0 +1,291 @@
+//! Command-line tool turning the captures and stats saved by a profiled program
+//! into reports, see `rallo --help`.
+
+use std::{
+    fs::{self, File},
+    io::{self, IsTerminal, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use rallo::{Capture, FirefoxProfile, Stats, Symbolizer};
+
+const USAGE: &str = "\
+Usage: rallo <command> [options] <input>...
+
+Reads captures written by `Capture::write_file` or `RalloAllocator::stream`,
+and stats written by `Stats::write_json`.
+
+Commands:
+  summary <input>          Print the totals and the call sites which allocated the most
+  html <input>             Write the flamegraph page
+  firefox <input>          Write a profile for the Firefox Profiler
+  json <input>             Write the symbolized stats, to be loaded again without symbolizing
+  diff <before> <after>    Print the call sites whose allocations changed the most
+
+Options:
+  -o, --output <path>      Where html, firefox and json write (required by them)
+  -n, --top <n>            Number of call sites printed by summary and diff [default: 10]
+  --thread <id>            Only keep the events of a thread
+  --region <name>          Only keep the events inside a region
+  --peak                   Only keep the allocations live when the memory peaked
+  --by-region              Show the regions as the first levels of html and firefox
+  -s, --search-path <dir>  Also look for the executables and their debug information there
+  -h, --help               Print this help
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Summary,
+    Html,
+    Firefox,
+    Json,
+    Diff,
+}
+
+#[derive(Debug)]
+struct Args {
+    command: Command,
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    top: usize,
+    thread: Option<u64>,
+    region: Option<String>,
+    peak: bool,
+    by_region: bool,
+    search_paths: Vec<PathBuf>,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let command = match args.next().as_deref() {
+            Some("summary") => Command::Summary,
+            Some("html") => Command::Html,
+            Some("firefox") => Command::Firefox,
+            Some("json") => Command::Json,
+            Some("diff") => Command::Diff,
+            Some(command) => return Err(format!("unknown command `{command}`")),
+            None => return Err("missing command".into()),
+        };
+
+        let mut parsed = Args {
+            command,
+            inputs: Vec::new(),
+            output: None,
+            top: 10,
+            thread: None,
+            region: None,
+            peak: false,
+            by_region: false,
+            search_paths: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("missing value for `{name}`"));
+            match arg.as_str() {
+                "-o" | "--output" => parsed.output = Some(value(&arg)?.into()),
+                "-n" | "--top" => {
+                    parsed.top = value(&arg)?
+                        .parse()
+                        .map_err(|e| format!("invalid `{arg}`: {e}"))?
+                }
+                "--thread" => {
+                    parsed.thread = Some(
+                        value(&arg)?
+                            .parse()
+                            .map_err(|e| format!("invalid `{arg}`: {e}"))?,
+                    )
+                }
+                "--region" => parsed.region = Some(value(&arg)?),
+                "--peak" => parsed.peak = true,
+                "--by-region" => parsed.by_region = true,
+                "-s" | "--search-path" => parsed.search_paths.push(value(&arg)?.into()),
+                _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+                _ => parsed.inputs.push(arg.into()),
+            }
+        }
+
+        let inputs = if command == Command::Diff { 2 } else { 1 };
+        if parsed.inputs.len() != inputs {
+            return Err(format!(
+                "expected {inputs} input(s), got {}",
+                parsed.inputs.len()
+            ));
+        }
+        if command != Command::Summary && command != Command::Diff && parsed.output.is_none() {
+            return Err("missing `--output`".into());
+        }
+        Ok(parsed)
+    }
+
+    /// Load, symbolize and filter an input
+    fn load(&self, path: &Path) -> io::Result<Stats> {
+        let mut magic = [0; 8];
+        let is_capture = File::open(path)?
+            .read_exact(&mut magic)
+            .is_ok_and(|()| &magic == b"RALLOCAP");
+        let mut stats = if is_capture {
+            let capture = Capture::read_file(path)?;
+            let mut symbolizer = self
+                .search_paths
+                .iter()
+                .fold(Symbolizer::new(), |symbolizer, dir| {
+                    symbolizer.search_path(dir)
+                });
+            let progress = io::stderr().is_terminal();
+            if progress {
+                symbolizer = symbolizer.progress(|resolved, total| {
+                    eprint!("\rsymbolizing: {resolved}/{total} addresses");
+                });
+            }
+            let stats = symbolizer.symbolize(capture);
+            if progress {
+                eprintln!();
+            }
+            stats
+        } else {
+            Stats::read_json(path)?
+        };
+
+        if let Some(thread) = self.thread {
+            stats = stats.filter_by_thread(thread);
+        }
+        if let Some(region) = &self.region {
+            stats = stats.filter_by_region(region);
+        }
+        if self.peak {
+            stats = stats.into_peak().map_err(io::Error::other)?;
+        }
+        Ok(stats)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+    if args
+        .peek()
+        .is_none_or(|arg| arg == "-h" || arg == "--help" || arg == "help")
+    {
+        print!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("rallo: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rallo: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> io::Result<()> {
+    let stats = args.load(&args.inputs[0])?;
+    let output = args.output.as_deref();
+    match args.command {
+        Command::Summary => print_summary(&stats, args.top),
+        Command::Html => {
+            let tree = if args.by_region {
+                stats.into_tree_by_region()
+            } else {
+                stats.into_tree()
+            };
+            let tree = tree.map_err(io::Error::other)?;
+            fs::write(output.unwrap(), tree.flamegraph_html())?;
+        }
+        Command::Firefox => {
+            let profile = if args.by_region {
+                FirefoxProfile::from_stats_by_region(stats)
+            } else {
+                FirefoxProfile::from_stats(stats)
+            };
+            profile
+                .map_err(io::Error::other)?
+                .write_json(output.unwrap())?;
+        }
+        Command::Json => stats.write_json(output.unwrap())?,
+        Command::Diff => {
+            let after = args.load(&args.inputs[1])?;
+            print_diff(&stats, &after, args.top);
+        }
+    }
+    Ok(())
+}
+
+fn print_summary(stats: &Stats, top: usize) {
+    let counters = &stats.counters;
+    println!(
+        "allocations:   {} ({} bytes)",
+        counters.total_allocations, counters.total_bytes_allocated
+    );
+    println!(
+        "deallocations: {} ({} bytes)",
+        counters.total_deallocations, counters.total_bytes_freed
+    );
+    println!("reallocations: {}", counters.total_reallocations);
+    println!("peak:          {} bytes", counters.peak_live_bytes);
+    println!(
+        "recorded:      {} allocations, {} deallocations, {} reallocations, {} failures",
+        stats.allocations.len(),
+        stats.deallocations.len(),
+        stats.reallocations.len(),
+        stats.failed_allocations.len()
+    );
+    if stats.dropped_events != 0 {
+        println!("dropped:       {} events", stats.dropped_events);
+    }
+    if let Some(rate) = stats.sampling_rate {
+        println!("sampled:       one byte every {rate} (the sizes below are estimated)");
+    }
+    if let Some(peak) = stats.peak {
+        println!(
+            "live at peak:  {} bytes, after {:?}",
+            peak.live_bytes, peak.timestamp
+        );
+    }
+
+    println!("\ntop call sites:");
+    for site in stats.call_sites().into_iter().take(top) {
+        println!("  {} allocation(s), {} bytes", site.count, site.bytes);
+        for frame in site.frames {
+            println!("      at {frame}");
+        }
+    }
+}
+
+fn print_diff(before: &Stats, after: &Stats, top: usize) {
+    let total = |stats: &Stats| -> i64 {
+        stats
+            .call_sites()
+            .iter()
+            .map(|site| site.bytes as i64)
+            .sum()
+    };
+    println!(
+        "allocated bytes: {} -> {} ({:+})",
+        total(before),
+        total(after),
+        total(after) - total(before)
+    );
+
+    println!("\ntop changes:");
+    for diff in before.diff(after).into_iter().take(top) {
+        println!(
+            "  {:+} bytes ({} -> {}), {:+} allocation(s) ({} -> {})",
+            diff.bytes_delta(),
+            diff.before.bytes,
+            diff.after.bytes,
+            diff.count_delta(),
+            diff.before.count,
+            diff.after.count
+        );
+        for frame in diff.frames {
+            println!("      at {frame}");
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! Binary format of the events streamed to disk, see [`crate::RalloAllocator::stream`].
+//!
+//! A capture starts with a header, followed by one record per event and by a
+//! trailer with the totals of the session. All the integers are little endian.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    counters::Counters,
+    stats::{Allocation, FrameInfo, Stats},
+    symbolize::{self, Module},
+};
+
+const MAGIC: &[u8; 8] = b"RALLOCAP";
+const VERSION: u32 = 3;
+/// Oldest version still read: without [`RawEvent::truncated`]
+const MIN_VERSION: u32 = 2;
+
+const TAG_ALLOCATION: u8 = 1;
+const TAG_DEALLOCATION: u8 = 2;
+const TAG_REALLOCATION: u8 = 3;
+const TAG_FAILED_ALLOCATION: u8 = 4;
+const TAG_TRAILER: u8 = 0xFF;
+
+/// Kind of a captured event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawEventKind {
+    Allocation,
+    Deallocation,
+    Reallocation,
+    FailedAllocation,
+}
+
+impl RawEventKind {
+    fn tag(self) -> u8 {
+        match self {
+            RawEventKind::Allocation => TAG_ALLOCATION,
+            RawEventKind::Deallocation => TAG_DEALLOCATION,
+            RawEventKind::Reallocation => TAG_REALLOCATION,
+            RawEventKind::FailedAllocation => TAG_FAILED_ALLOCATION,
+        }
+    }
+}
+
+/// An event as recorded by the allocator, before symbolization.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawEvent {
+    pub kind: RawEventKind,
+    /// Size of the block (the new size, for reallocations)
+    pub size: usize,
+    /// Size of the block before a reallocation
+    pub previous_size: usize,
+    /// Address of the block (the new address, for reallocations)
+    pub address: usize,
+    /// Address of the block before a reallocation, 0 if none
+    pub previous_address: usize,
+    pub weight: f64,
+    pub sequence: u64,
+    /// Nanoseconds elapsed since the start of the session
+    pub timestamp: u64,
+    pub thread_id: u64,
+    pub thread_name: Option<String>,
+    pub region: Vec<String>,
+    /// Instruction pointers, innermost first
+    pub frames: Vec<usize>,
+    /// Whether the stack was deeper than the recorded frames
+    pub truncated: bool,
+}
+
+/// The events of a session with raw instruction pointers, as recorded.
+///
+/// Symbolize them in the recording process with [`Capture::into_stats`], or
+/// anywhere else from the executable files with [`crate::Symbolizer`].
+#[derive(Debug, Default)]
+pub struct Capture {
+    /// Executable files mapped in the recording process, to symbolize the frames
+    pub modules: Vec<Module>,
+    /// Events in the order they were written, which is roughly the order they happened
+    pub events: Vec<RawEvent>,
+    /// Number of events lost because the writer didn't keep up
+    pub dropped_events: usize,
+    pub sampling_rate: Option<usize>,
+    pub counters: Counters,
+}
+
+impl Capture {
+    /// Read a capture written by [`Capture::write`] or [`crate::RalloAllocator::stream`].
+    ///
+    /// A capture cut short, e.g. because the process was killed, is read up to
+    /// its last complete event.
+    pub fn read<R: Read>(reader: R) -> io::Result<Capture> {
+        let mut reader = Reader(io::BufReader::new(reader));
+
+        let mut magic = [0; 8];
+        reader.0.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a rallo capture",
+            ));
+        }
+        let version = reader.u32()?;
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported capture version {version}"),
+            ));
+        }
+        let sampling_rate = reader.u64()? as usize;
+        let modules = (0..reader.u32()?)
+            .map(|_| reader.module())
+            .collect::<io::Result<_>>()?;
+
+        let mut capture = Capture {
+            modules,
+            sampling_rate: (sampling_rate != 0).then_some(sampling_rate),
+            ..Capture::default()
+        };
+        loop {
+            let tag = match reader.u8() {
+                Ok(tag) => tag,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let kind = match tag {
+                TAG_ALLOCATION => RawEventKind::Allocation,
+                TAG_DEALLOCATION => RawEventKind::Deallocation,
+                TAG_REALLOCATION => RawEventKind::Reallocation,
+                TAG_FAILED_ALLOCATION => RawEventKind::FailedAllocation,
+                TAG_TRAILER => {
+                    match reader.trailer() {
+                        Ok((dropped_events, counters)) => {
+                            capture.dropped_events = dropped_events;
+                            capture.counters = counters;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                        Err(e) => return Err(e),
+                    }
+                    break;
+                }
+                tag => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown record {tag}"),
+                    ));
+                }
+            };
+            match reader.event(kind, version) {
+                Ok(event) => capture.events.push(event),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(capture)
+    }
+
+    /// Read the capture file at `path`, see [`Capture::read`].
+    pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Capture> {
+        Capture::read(std::fs::File::open(path)?)
+    }
+
+    /// Write the capture, to be read by [`Capture::read`].
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = CaptureWriter::new(writer, self.sampling_rate, &self.modules)?;
+        for event in &self.events {
+            writer.write_event(event)?;
+        }
+        writer.finish(self.dropped_events, self.counters)?;
+        Ok(())
+    }
+
+    /// Write the capture to the file at `path`, see [`Capture::write`].
+    pub fn write_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(std::fs::File::create(path)?)
+    }
+
+    /// Symbolize the events into [`Stats`].
+    ///
+    /// The instruction pointers are resolved against the running program:
+    /// the capture has to come from this same process. Otherwise use [`crate::Symbolizer`].
+    /// Each distinct instruction pointer is resolved once, by several threads
+    /// for the large captures.
+    pub fn into_stats(self) -> Stats {
+        self.into_stats_with(symbolize::resolve_in_process)
+    }
+
+    /// Build the [`Stats`] of the events, symbolizing their stacks with `resolve`:
+    /// given the distinct instruction pointers, it returns their frames
+    pub(crate) fn into_stats_with<F>(self, resolve: F) -> Stats
+    where
+        F: FnOnce(&[usize]) -> HashMap<usize, Vec<FrameInfo>>,
+    {
+        let mut ips: Vec<usize> = self
+            .events
+            .iter()
+            .flat_map(|event| event.frames.iter().copied())
+            .collect();
+        ips.sort_unstable();
+        ips.dedup();
+        let frames = resolve(&ips);
+
+        let mut stats = Stats {
+            allocations: VecDeque::new(),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: self.dropped_events,
+            sampling_rate: self.sampling_rate,
+            counters: self.counters,
+            peak: None,
+        };
+
+        for event in self.events {
+            let (allocation_size, deallocation_size) = match event.kind {
+                RawEventKind::Deallocation => (0, event.size),
+                RawEventKind::Reallocation => (event.size, event.previous_size),
+                RawEventKind::Allocation | RawEventKind::FailedAllocation => (event.size, 0),
+            };
+            let allocation = Allocation {
+                allocation_size,
+                deallocation_size,
+                address: event.address,
+                previous_address: (event.previous_address != 0).then_some(event.previous_address),
+                weight: event.weight,
+                sequence: event.sequence,
+                timestamp: Duration::from_nanos(event.timestamp),
+                thread_id: event.thread_id,
+                thread_name: event.thread_name,
+                region: event.region,
+                truncated: event.truncated,
+                // Outermost frame first
+                stack: event
+                    .frames
+                    .iter()
+                    .rev()
+                    .flat_map(|ip| frames[ip].iter().cloned())
+                    .collect(),
+            };
+            // Newest first, as the stats collected in memory
+            let events = match event.kind {
+                RawEventKind::Allocation => &mut stats.allocations,
+                RawEventKind::Deallocation => &mut stats.deallocations,
+                RawEventKind::Reallocation => &mut stats.reallocations,
+                RawEventKind::FailedAllocation => &mut stats.failed_allocations,
+            };
+            events.push_front(allocation);
+        }
+
+        for events in [
+            &mut stats.allocations,
+            &mut stats.deallocations,
+            &mut stats.reallocations,
+            &mut stats.failed_allocations,
+        ] {
+            events
+                .make_contiguous()
+                .sort_by_key(|event| Reverse(event.sequence));
+        }
+
+        stats
+    }
+}
+
+/// Writes a capture, see [`Capture::read`] for the other side.
+pub(crate) struct CaptureWriter<W: Write> {
+    writer: io::BufWriter<W>,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub(crate) fn new(
+        writer: W,
+        sampling_rate: Option<usize>,
+        modules: &[Module],
+    ) -> io::Result<Self> {
+        let mut w = io::BufWriter::new(writer);
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(sampling_rate.unwrap_or(0) as u64).to_le_bytes())?;
+        w.write_all(&(modules.len() as u32).to_le_bytes())?;
+        for module in modules {
+            for value in [module.start, module.end, module.offset] {
+                w.write_all(&(value as u64).to_le_bytes())?;
+            }
+            write_str(&mut w, &module.path.to_string_lossy())?;
+            // An empty build ID stands for none
+            let build_id = module.build_id.as_deref().unwrap_or_default();
+            w.write_all(&(build_id.len() as u32).to_le_bytes())?;
+            w.write_all(build_id)?;
+        }
+        Ok(CaptureWriter { writer: w })
+    }
+
+    pub(crate) fn write_event(&mut self, event: &RawEvent) -> io::Result<()> {
+        let w = &mut self.writer;
+        w.write_all(&[event.kind.tag()])?;
+        for value in [
+            event.size as u64,
+            event.previous_size as u64,
+            event.address as u64,
+            event.previous_address as u64,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        w.write_all(&event.weight.to_le_bytes())?;
+        for value in [event.sequence, event.timestamp, event.thread_id] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        write_str(w, event.thread_name.as_deref().unwrap_or_default())?;
+        w.write_all(&(event.region.len() as u32).to_le_bytes())?;
+        for name in &event.region {
+            write_str(w, name)?;
+        }
+        w.write_all(&(event.frames.len() as u32).to_le_bytes())?;
+        for ip in &event.frames {
+            w.write_all(&(*ip as u64).to_le_bytes())?;
+        }
+        w.write_all(&[event.truncated as u8])?;
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Write the trailer and return the underlying writer
+    pub(crate) fn finish(mut self, dropped_events: usize, counters: Counters) -> io::Result<W> {
+        let w = &mut self.writer;
+        w.write_all(&[TAG_TRAILER])?;
+        w.write_all(&(dropped_events as u64).to_le_bytes())?;
+        for value in [
+            counters.live_bytes as u64,
+            counters.peak_live_bytes as u64,
+            counters.total_allocations,
+            counters.total_deallocations,
+            counters.total_reallocations,
+            counters.total_bytes_allocated,
+            counters.total_bytes_freed,
+        ] {
+            w.write_all(&value.to_le_bytes())?;
+        }
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut bytes = [0; 1];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.0.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.0.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let mut bytes = vec![0; len];
+        self.0.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn module(&mut self) -> io::Result<Module> {
+        let start = self.u64()? as usize;
+        let end = self.u64()? as usize;
+        let offset = self.u64()? as usize;
+        let path = self.string()?.into();
+        let mut build_id = vec![0; self.u32()? as usize];
+        self.0.read_exact(&mut build_id)?;
+
+        Ok(Module {
+            start,
+            end,
+            offset,
+            path,
+            build_id: Some(build_id).filter(|id| !id.is_empty()),
+        })
+    }
+
+    fn event(&mut self, kind: RawEventKind, version: u32) -> io::Result<RawEvent> {
+        let size = self.u64()? as usize;
+        let previous_size = self.u64()? as usize;
+        let address = self.u64()? as usize;
+        let previous_address = self.u64()? as usize;
+        let weight = f64::from_bits(self.u64()?);
+        let sequence = self.u64()?;
+        let timestamp = self.u64()?;
+        let thread_id = self.u64()?;
+        let thread_name = Some(self.string()?).filter(|name| !name.is_empty());
+        let region = (0..self.u32()?)
+            .map(|_| self.string())
+            .collect::<io::Result<_>>()?;
+        let frames = (0..self.u32()?)
+            .map(|_| self.u64().map(|ip| ip as usize))
+            .collect::<io::Result<_>>()?;
+        let truncated = version >= 3 && self.u8()? != 0;
+
+        Ok(RawEvent {
+            kind,
+            size,
+            previous_size,
+            address,
+            previous_address,
+            weight,
+            sequence,
+            timestamp,
+            thread_id,
+            thread_name,
+            region,
+            frames,
+            truncated,
+        })
+    }
+
+    fn trailer(&mut self) -> io::Result<(usize, Counters)> {
+        let dropped_events = self.u64()? as usize;
+        Ok((dropped_events, self.counters()?))
+    }
+
+    fn counters(&mut self) -> io::Result<Counters> {
+        Ok(Counters {
+            live_bytes: self.u64()? as usize,
+            peak_live_bytes: self.u64()? as usize,
+            total_allocations: self.u64()?,
+            total_deallocations: self.u64()?,
+            total_reallocations: self.u64()?,
+            total_bytes_allocated: self.u64()?,
+            total_bytes_freed: self.u64()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let event = RawEvent {
+            kind: RawEventKind::Reallocation,
+            size: 64,
+            previous_size: 32,
+            address: 0x2000,
+            previous_address: 0x1000,
+            weight: 1.5,
+            sequence: 7,
+            timestamp: 1_000,
+            thread_id: 42,
+            thread_name: Some("worker".into()),
+            region: vec!["index".into(), "merge".into()],
+            frames: vec![0x10, 0x20, 0x30],
+            truncated: true,
+        };
+        let counters = Counters {
+            total_reallocations: 1,
+            ..Counters::default()
+        };
+
+        let modules = vec![Module {
+            start: 0x1000,
+            end: 0x2000,
+            offset: 0x400,
+            path: "/usr/bin/service".into(),
+            build_id: Some(vec![0xab, 0xcd]),
+        }];
+        let mut writer = CaptureWriter::new(Vec::new(), Some(4096), &modules).unwrap();
+        writer.write_event(&event).unwrap();
+        let bytes = writer.finish(3, counters).unwrap();
+
+        let capture = Capture::read(bytes.as_slice()).unwrap();
+        assert_eq!(capture.events, vec![event.clone()]);
+        assert_eq!(capture.modules, modules);
+        assert_eq!(capture.dropped_events, 3);
+        assert_eq!(capture.sampling_rate, Some(4096));
+        assert_eq!(capture.counters, counters);
+
+        // A truncated capture keeps its complete events
+        let capture = Capture::read(&bytes[..bytes.len() - 20]).unwrap();
+        assert_eq!(capture.events, vec![event.clone()]);
+        assert_eq!(capture.dropped_events, 0);
+        let capture = Capture::read(&bytes[..bytes.len() - 80]).unwrap();
+        assert!(capture.events.is_empty());
+
+        assert!(Capture::read(&b"RALLOCAX"[..]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// Totals kept by [`crate::RalloAllocator`] for every event, whether tracking or not.
+///
+/// Reading them is cheap: no backtrace is captured. Values read while other threads
+/// allocate may be slightly out of sync with each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+    /// Bytes currently allocated. For a session, the net amount allocated since it
+    /// started, 0 if it freed more than it allocated
+    pub live_bytes: usize,
+    /// Highest value reached by `live_bytes`
+    pub peak_live_bytes: usize,
+    /// Number of allocations
+    pub total_allocations: u64,
+    /// Number of deallocations
+    pub total_deallocations: u64,
+    /// Number of reallocations
+    pub total_reallocations: u64,
+    /// Bytes ever allocated, including the new size of the reallocations
+    pub total_bytes_allocated: u64,
+    /// Bytes ever freed, including the old size of the reallocations
+    pub total_bytes_freed: u64,
+}
+
+/// Atomic counterpart of [`Counters`], updated by the allocator.
+pub(crate) struct AtomicCounters {
+    // Signed: a session can free memory allocated before it started
+    live_bytes: AtomicIsize,
+    peak_live_bytes: AtomicIsize,
+    total_allocations: AtomicU64,
+    total_deallocations: AtomicU64,
+    total_reallocations: AtomicU64,
+    total_bytes_allocated: AtomicU64,
+    total_bytes_freed: AtomicU64,
+}
+
+impl AtomicCounters {
+    pub(crate) const fn new() -> Self {
+        AtomicCounters {
+            live_bytes: AtomicIsize::new(0),
+            peak_live_bytes: AtomicIsize::new(0),
+            total_allocations: AtomicU64::new(0),
+            total_deallocations: AtomicU64::new(0),
+            total_reallocations: AtomicU64::new(0),
+            total_bytes_allocated: AtomicU64::new(0),
+            total_bytes_freed: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn on_alloc(&self, size: usize) {
+        self.total_allocations.fetch_add(1, Ordering::Relaxed);
+        self.total_bytes_allocated
+            .fetch_add(size as u64, Ordering::Relaxed);
+        self.grow(size as isize);
+    }
+
+    pub(crate) fn on_dealloc(&self, size: usize) {
+        self.total_deallocations.fetch_add(1, Ordering::Relaxed);
+        self.total_bytes_freed
+            .fetch_add(size as u64, Ordering::Relaxed);
+        self.live_bytes.fetch_sub(size as isize, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_realloc(&self, old_size: usize, new_size: usize) {
+        self.total_reallocations.fetch_add(1, Ordering::Relaxed);
+        self.total_bytes_allocated
+            .fetch_add(new_size as u64, Ordering::Relaxed);
+        self.total_bytes_freed
+            .fetch_add(old_size as u64, Ordering::Relaxed);
+        self.grow(new_size as isize - old_size as isize);
+    }
+
+    fn grow(&self, size: isize) {
+        let live = self.live_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_live_bytes.fetch_max(live, Ordering::Relaxed);
+    }
+
+    /// Net bytes allocated, negative if more were freed
+    pub(crate) fn live_bytes(&self) -> isize {
+        self.live_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Start counting again from 0
+    pub(crate) fn reset(&self) {
+        self.live_bytes.store(0, Ordering::Relaxed);
+        self.peak_live_bytes.store(0, Ordering::Relaxed);
+        self.total_allocations.store(0, Ordering::Relaxed);
+        self.total_deallocations.store(0, Ordering::Relaxed);
+        self.total_reallocations.store(0, Ordering::Relaxed);
+        self.total_bytes_allocated.store(0, Ordering::Relaxed);
+        self.total_bytes_freed.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Counters {
+        Counters {
+            live_bytes: self.live_bytes.load(Ordering::Relaxed).max(0) as usize,
+            peak_live_bytes: self.peak_live_bytes.load(Ordering::Relaxed).max(0) as usize,
+            total_allocations: self.total_allocations.load(Ordering::Relaxed),
+            total_deallocations: self.total_deallocations.load(Ordering::Relaxed),
+            total_reallocations: self.total_reallocations.load(Ordering::Relaxed),
+            total_bytes_allocated: self.total_bytes_allocated.load(Ordering::Relaxed),
+            total_bytes_freed: self.total_bytes_freed.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use crate::sampling;
+
+/// Which allocations of a session fail on purpose, see [`crate::TrackOptions::fail_allocations`].
+///
+/// Allocations and reallocations are counted together, from 0 at the start of the session.
+/// A failed call returns a null pointer, as if the memory was exhausted.
+#[derive(Debug, Clone, Copy)]
+pub enum FaultInjection {
+    /// Fail the `n`-th call only
+    Nth(u64),
+    /// Fail the calls requesting a number of bytes accepted by the predicate
+    Size(fn(usize) -> bool),
+    /// Fail each call with probability `rate`. The same `seed` fails the same
+    /// calls, as long as they happen in the same order
+    Random { rate: f64, seed: u64 },
+}
+
+impl FaultInjection {
+    /// Whether the `index`-th call of the session, requesting `size` bytes, fails
+    pub(crate) fn should_fail(&self, index: u64, size: usize) -> bool {
+        match *self {
+            FaultInjection::Nth(n) => index == n,
+            FaultInjection::Size(predicate) => predicate(size),
+            FaultInjection::Random { rate, seed } => {
+                let random = sampling::mix(seed ^ sampling::mix(index));
+                // Uniform in [0, 1)
+                ((random >> 11) as f64 / (1_u64 << 53) as f64) < rate
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_faults_are_seeded() {
+        let failures = |seed| {
+            (0..10_000)
+                .filter(|index| FaultInjection::Random { rate: 0.1, seed }.should_fail(*index, 8))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(failures(42), failures(42));
+        assert_ne!(failures(42), failures(43));
+        let count = failures(42).len();
+        assert!((800..1200).contains(&count), "{count} failures");
+    }
+}
@@ -10,7 +10,7 @@ use std::{
 use fxprof_processed_profile::debugid::DebugId;
 use rustc_demangle::try_demangle;
 
-use crate::stats::{Allocation, FrameInfo as StatsFrameInfo, Stats};
+use crate::stats::{Allocation, EventKind, FrameInfo as StatsFrameInfo, Stats};
 use fxprof_processed_profile::ReferenceTimestamp;
 use fxprof_processed_profile::{
     CategoryColor, CategoryHandle, CategoryPairHandle, Frame as FxFrame,
@@ -29,7 +29,17 @@ pub struct FirefoxProfile {
 impl FirefoxProfile {
     /// Build a Firefox profile from the recorded allocation metadata.
     pub fn from_stats(stats: Stats) -> Result<Self, Cow<'static, str>> {
-        let mut builder = FirefoxProfileBuilder::new()?;
+        Self::build(stats, false)
+    }
+
+    /// Same as [`FirefoxProfile::from_stats`], with the regions of the events
+    /// as the outermost frames of their stacks, see [`crate::region`].
+    pub fn from_stats_by_region(stats: Stats) -> Result<Self, Cow<'static, str>> {
+        Self::build(stats, true)
+    }
+
+    fn build(stats: Stats, by_region: bool) -> Result<Self, Cow<'static, str>> {
+        let mut builder = FirefoxProfileBuilder::new(by_region)?;
         builder.ingest(stats);
         let profile = builder.finish();
         Ok(Self { inner: profile })
@@ -60,16 +70,17 @@ impl FirefoxProfile {
 struct FirefoxProfileBuilder {
     profile: Profile,
     process: ProcessHandle,
-    thread: ThreadHandle,
+    threads: HashMap<u64, ThreadHandle>,
     categories: CategoryHandles,
     cwd: PathBuf,
     symbol_registry: SymbolRegistry,
-    current_time_ns: u64,
     last_timestamp: Timestamp,
+    /// Whether the stacks start with the regions of the events
+    by_region: bool,
 }
 
 impl FirefoxProfileBuilder {
-    fn new() -> Result<Self, Cow<'static, str>> {
+    fn new(by_region: bool) -> Result<Self, Cow<'static, str>> {
         let mut profile = Profile::new(
             "rallo memory profile",
             ReferenceTimestamp::from(SystemTime::now()),
@@ -77,16 +88,11 @@ impl FirefoxProfileBuilder {
         );
         profile.set_symbolicated(true);
 
-        let process = profile.add_process("rallo", 1, Timestamp::from_millis_since_reference(0.0));
-        let thread = profile.add_thread(
-            process,
-            1,
+        let process = profile.add_process(
+            "rallo",
+            std::process::id(),
             Timestamp::from_millis_since_reference(0.0),
-            true,
         );
-        profile.set_thread_name(thread, "Allocations");
-        profile.add_initial_visible_thread(thread);
-        profile.add_initial_selected_thread(thread);
 
         let cwd = std::env::current_dir()
             .map_err(|e| Cow::Owned(format!("failed to get current directory: {e:?}")))?;
@@ -96,82 +102,176 @@ impl FirefoxProfileBuilder {
         Ok(Self {
             profile,
             process,
-            thread,
+            threads: HashMap::new(),
             categories,
             cwd,
             symbol_registry: SymbolRegistry::new(),
-            current_time_ns: 0,
             last_timestamp: Timestamp::from_nanos_since_reference(0),
+            by_region,
         })
     }
 
     fn ingest(&mut self, stats: Stats) {
-        self.process_allocation_iter(
-            stats.allocations.into_iter().rev(),
-            AllocationKind::Allocation,
-        );
-        self.process_allocation_iter(
-            stats.deallocations.into_iter().rev(),
-            AllocationKind::Deallocation,
-        );
+        let mut notes = Vec::new();
+        if let Some(peak) = stats.peak {
+            notes.push(format!(
+                "allocations live at the peak: {} bytes after {:?}",
+                peak.live_bytes, peak.timestamp
+            ));
+        }
+        if let Some(sampling_rate) = stats.sampling_rate {
+            notes.push(format!("estimated from 1 byte every {sampling_rate}"));
+        }
+        if stats.dropped_events > 0 {
+            notes.push(format!(
+                "incomplete: {} events dropped",
+                stats.dropped_events
+            ));
+        }
+        if !notes.is_empty() {
+            self.profile
+                .set_product(&format!("rallo memory profile ({})", notes.join(", ")));
+        }
+
+        self.process_timeline(stats.into_timeline());
     }
 
     fn finish(mut self) -> Profile {
+        // The Firefox Profiler can't load a profile without threads: without
+        // events, keep the thread of the older profiles
+        if self.threads.is_empty() {
+            let thread = self.add_thread(1, "Allocations", true);
+            self.threads.insert(0, thread);
+        }
         self.profile
             .set_process_end_time(self.process, self.last_timestamp);
-        self.profile
-            .set_thread_end_time(self.thread, self.last_timestamp);
+        for thread in self.threads.values() {
+            self.profile
+                .set_thread_end_time(*thread, self.last_timestamp);
+        }
         self.symbol_registry.finalize(&mut self.profile);
         self.profile
     }
 
-    fn process_allocation_iter<I>(&mut self, allocations: I, kind: AllocationKind)
-    where
-        I: Iterator<Item = Allocation>,
-    {
-        for allocation in allocations {
-            let size = match kind {
-                AllocationKind::Allocation => allocation.allocation_size,
-                AllocationKind::Deallocation => allocation.deallocation_size,
+    fn process_timeline(&mut self, timeline: Vec<(EventKind, Allocation)>) {
+        for (kind, allocation) in timeline {
+            let thread =
+                self.thread_handle(allocation.thread_id, allocation.thread_name.as_deref());
+            let stack = self.build_stack(thread, &allocation);
+
+            // A reallocation releases the old block and takes the new one
+            // from the same call site
+            let samples = match kind {
+                EventKind::Allocation => [
+                    (
+                        allocation.address,
+                        usize_to_i64(allocation.estimated_allocation_size()),
+                    ),
+                    (0, 0),
+                ],
+                EventKind::Deallocation => [
+                    (
+                        allocation.address,
+                        -usize_to_i64(allocation.estimated_deallocation_size()),
+                    ),
+                    (0, 0),
+                ],
+                EventKind::Reallocation => [
+                    (
+                        allocation.previous_address.unwrap_or(allocation.address),
+                        -usize_to_i64(allocation.estimated_deallocation_size()),
+                    ),
+                    (
+                        allocation.address,
+                        usize_to_i64(allocation.estimated_allocation_size()),
+                    ),
+                ],
             };
-            if size == 0 {
-                continue;
+
+            let timestamp =
+                Timestamp::from_nanos_since_reference(allocation.timestamp.as_nanos() as u64);
+            self.last_timestamp = self.last_timestamp.max(timestamp);
+
+            for (address, size) in samples {
+                if size == 0 {
+                    continue;
+                }
+
+                self.profile
+                    .add_allocation_sample(thread, timestamp, stack, address as u64, size);
             }
+        }
+    }
 
-            self.current_time_ns = self.current_time_ns.saturating_add(1_000_000);
-            self.last_timestamp = Timestamp::from_nanos_since_reference(self.current_time_ns);
+    /// Profiler thread of the real thread `thread_id`, created on first use.
+    fn thread_handle(&mut self, thread_id: u64, thread_name: Option<&str>) -> ThreadHandle {
+        if let Some(thread) = self.threads.get(&thread_id) {
+            return *thread;
+        }
 
-            let stack = self.build_stack(&allocation.stack);
-            let address = allocation.address as u64;
-            let size = match kind {
-                AllocationKind::Allocation => usize_to_i64(size),
-                AllocationKind::Deallocation => -usize_to_i64(size),
-            };
+        // The profile has 32-bit thread ids: the larger ones are replaced by
+        // the position of the thread, the unnamed threads keep them in their name
+        let tid = u32::try_from(thread_id).unwrap_or(self.threads.len() as u32 + 1);
+        let name = match thread_name {
+            Some(name) => name.to_string(),
+            None => format!("Thread {thread_id}"),
+        };
+        let thread = self.add_thread(tid, &name, thread_name == Some("main"));
 
-            self.profile.add_allocation_sample(
-                self.thread,
-                self.last_timestamp,
-                stack,
-                address,
-                size,
-            );
+        self.threads.insert(thread_id, thread);
+        thread
+    }
+
+    /// Add a thread to the profile, selected if it is the first or the main one
+    fn add_thread(&mut self, tid: u32, name: &str, is_main: bool) -> ThreadHandle {
+        let thread = self.profile.add_thread(
+            self.process,
+            tid,
+            Timestamp::from_millis_since_reference(0.0),
+            is_main,
+        );
+        self.profile.set_thread_name(thread, name);
+        self.profile.add_initial_visible_thread(thread);
+        if self.threads.is_empty() || is_main {
+            self.profile.clear_initial_selected_threads();
+            self.profile.add_initial_selected_thread(thread);
         }
+        thread
     }
 
     fn build_stack(
         &mut self,
-        stack: &std::collections::VecDeque<StatsFrameInfo>,
+        thread: ThreadHandle,
+        allocation: &Allocation,
     ) -> Option<StackHandle> {
-        let frames: Vec<FxFrameInfo> = stack
-            .iter()
-            .filter_map(|frame| self.convert_frame(frame))
-            .collect();
+        let mut frames: Vec<FxFrameInfo> = Vec::new();
+        if self.by_region {
+            for name in &allocation.region {
+                frames.push(FxFrameInfo {
+                    frame: FxFrame::Label(self.profile.intern_string(name)),
+                    category_pair: self.categories.get(CategoryKind::Region),
+                    flags: FxFrameFlags::empty(),
+                });
+            }
+        }
+        if allocation.truncated {
+            frames.push(FxFrameInfo {
+                frame: FxFrame::Label(self.profile.intern_string("<truncated>")),
+                category_pair: self.categories.get(CategoryKind::Unknown),
+                flags: FxFrameFlags::empty(),
+            });
+        }
+        frames.extend(
+            allocation
+                .stack
+                .iter()
+                .filter_map(|frame| self.convert_frame(frame)),
+        );
 
         if frames.is_empty() {
             None
         } else {
-            self.profile
-                .intern_stack_frames(self.thread, frames.into_iter())
+            self.profile.intern_stack_frames(thread, frames.into_iter())
         }
     }
 
@@ -198,12 +298,6 @@ impl FirefoxProfileBuilder {
     }
 }
 
-#[derive(Clone, Copy)]
-enum AllocationKind {
-    Allocation,
-    Deallocation,
-}
-
 struct SymbolRegistry {
     libraries: HashMap<PathBuf, LibraryEntry>,
 }
@@ -383,6 +477,7 @@ enum CategoryKind {
     RustC,
     Dependencies,
     Unknown,
+    Region,
 }
 
 struct CategoryHandles {
@@ -391,6 +486,7 @@ struct CategoryHandles {
     rustc: CategoryPairHandle,
     dependencies: CategoryPairHandle,
     unknown: CategoryPairHandle,
+    region: CategoryPairHandle,
 }
 
 impl CategoryHandles {
@@ -408,6 +504,7 @@ impl CategoryHandles {
             .add_category("Dependencies", CategoryColor::Purple)
             .into();
         let unknown = CategoryHandle::OTHER.into();
+        let region = profile.add_category("Region", CategoryColor::Yellow).into();
 
         Self {
             application,
@@ -415,6 +512,7 @@ impl CategoryHandles {
             rustc,
             dependencies,
             unknown,
+            region,
         }
     }
 
@@ -425,6 +523,7 @@ impl CategoryHandles {
             CategoryKind::RustC => self.rustc,
             CategoryKind::Dependencies => self.dependencies,
             CategoryKind::Unknown => self.unknown,
+            CategoryKind::Region => self.region,
         }
     }
 }
@@ -451,9 +550,10 @@ fn determine_category(cwd: &Path, filename: Option<&Path>) -> CategoryKind {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::VecDeque;
+    use std::{collections::VecDeque, time::Duration};
 
     use super::*;
+    use crate::counters::Counters;
     use crate::stats::{Allocation, FrameInfo};
 
     #[test]
@@ -463,26 +563,50 @@ mod tests {
                 allocation_size: 128,
                 deallocation_size: 0,
                 address: 0xdead_beef,
+                previous_address: None,
+                weight: 1.0,
+                sequence: 0,
+                timestamp: Duration::ZERO,
+                thread_id: 1,
+                thread_name: Some("main".into()),
+                region: Vec::new(),
                 stack: VecDeque::from([FrameInfo {
                     filename: Some("src/lib.rs".into()),
                     colno: Some(1),
                     lineno: Some(10),
                     fn_address: Some(std::ptr::null_mut()),
                     fn_name: Some("my_function".into()),
+                    inlined: false,
                 }]),
+                truncated: false,
             }]),
             deallocations: VecDeque::from([Allocation {
                 allocation_size: 0,
                 deallocation_size: 128,
                 address: 0xdead_beef,
+                previous_address: None,
+                weight: 1.0,
+                sequence: 0,
+                timestamp: Duration::ZERO,
+                thread_id: 1,
+                thread_name: Some("main".into()),
+                region: Vec::new(),
                 stack: VecDeque::from([FrameInfo {
                     filename: Some("src/lib.rs".into()),
                     colno: Some(1),
                     lineno: Some(20),
                     fn_address: Some(std::ptr::null_mut()),
                     fn_name: Some("drop_my_function".into()),
+                    inlined: false,
                 }]),
+                truncated: false,
             }]),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
         };
 
         let profile = FirefoxProfile::from_stats(stats).expect("profile generation");
@@ -490,4 +614,78 @@ mod tests {
         assert!(json.contains("my_function"));
         assert!(json.contains("drop_my_function"));
     }
+
+    #[test]
+    fn firefox_profile_has_one_thread_per_real_thread() {
+        let allocation = |thread_id: u64, thread_name: &str| Allocation {
+            allocation_size: 64,
+            deallocation_size: 0,
+            address: 0xdead_beef,
+            previous_address: None,
+            weight: 1.0,
+            sequence: 0,
+            timestamp: Duration::ZERO,
+            thread_id,
+            thread_name: Some(thread_name.into()),
+            region: Vec::new(),
+            stack: VecDeque::from([FrameInfo {
+                filename: Some("src/lib.rs".into()),
+                colno: Some(1),
+                lineno: Some(10),
+                fn_address: Some(std::ptr::null_mut()),
+                fn_name: Some("my_function".into()),
+                inlined: false,
+            }]),
+            truncated: false,
+        };
+        let stats = Stats {
+            allocations: VecDeque::from([
+                allocation(1, "main"),
+                allocation(2, "worker"),
+                allocation((7 << 32) | 2, "wide"),
+            ]),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
+        };
+
+        let profile = FirefoxProfile::from_stats(stats).expect("profile generation");
+        let json: serde_json::Value =
+            serde_json::from_str(&profile.to_json_string().expect("serialize")).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 3);
+        // The main thread is named after the process
+        let main = threads.iter().find(|t| t["isMainThread"] == true).unwrap();
+        assert_eq!(main["tid"], "1");
+        let worker = threads.iter().find(|t| t["name"] == "worker").unwrap();
+        assert_eq!(worker["tid"], "2");
+        // Not merged with `worker`, whose id has the same lower bits
+        let wide = threads.iter().find(|t| t["name"] == "wide").unwrap();
+        assert_eq!(wide["tid"], "3");
+    }
+
+    #[test]
+    fn firefox_profile_without_events_has_a_thread() {
+        let stats = Stats {
+            allocations: VecDeque::new(),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
+        };
+
+        let profile = FirefoxProfile::from_stats(stats).expect("profile generation");
+        let json: serde_json::Value =
+            serde_json::from_str(&profile.to_json_string().expect("serialize")).unwrap();
+        let threads = json["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0]["isMainThread"], true);
+    }
 }
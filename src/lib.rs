@@ -2,10 +2,34 @@
 #![doc = include_str!("../README.md")]
 
 mod alloc;
+mod assertions;
+mod capture;
+mod counters;
+mod fault;
 mod firefox;
+mod mmap;
+mod region;
+mod report;
+mod sampling;
+#[cfg(unix)]
+mod signals;
+mod stacks;
 mod stats;
+mod symbolize;
+mod thread;
 mod unsafe_cell;
+mod unwind;
 
 pub use alloc::*;
+pub use assertions::*;
+pub use capture::{Capture, RawEvent, RawEventKind};
+pub use counters::Counters;
+pub use fault::FaultInjection;
 pub use firefox::*;
+pub use region::{MAX_REGION_DEPTH, RegionGuard, enter_region, region};
+pub use report::{CallSite, CallSiteDiff};
+#[cfg(unix)]
+pub use signals::ReportFormat;
 pub use stats::*;
+pub use symbolize::{Module, Symbolizer};
+pub use unwind::Unwinder;
@@ -0,0 +1,61 @@
+use std::alloc::Layout;
+
+/// Allocate room for `count` values of `T` directly from the OS, without going
+/// through the global allocator.
+///
+/// On unix the memory is an anonymous private mapping, so pages are committed
+/// only once touched. Aborts through `handle_alloc_error` if the memory is not available.
+pub(crate) fn map<T>(count: usize) -> *mut T {
+    let layout = Layout::array::<T>(count.max(1)).expect("buffer too large");
+    let ptr = unsafe { map_layout(layout) };
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    ptr as *mut T
+}
+
+/// Release the memory returned by `map`.
+///
+/// # Safety
+///
+/// `ptr` must come from `map::<T>(count)` and must not be used anymore.
+pub(crate) unsafe fn unmap<T>(ptr: *mut T, count: usize) {
+    let layout = Layout::array::<T>(count.max(1)).expect("buffer too large");
+    unsafe { unmap_layout(ptr as *mut u8, layout) }
+}
+
+#[cfg(unix)]
+unsafe fn map_layout(layout: Layout) -> *mut u8 {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            layout.size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        std::ptr::null_mut()
+    } else {
+        ptr as *mut u8
+    }
+}
+
+#[cfg(unix)]
+unsafe fn unmap_layout(ptr: *mut u8, layout: Layout) {
+    unsafe { libc::munmap(ptr as *mut libc::c_void, layout.size()) };
+}
+
+#[cfg(not(unix))]
+unsafe fn map_layout(layout: Layout) -> *mut u8 {
+    use std::alloc::GlobalAlloc;
+    unsafe { std::alloc::System.alloc_zeroed(layout) }
+}
+
+#[cfg(not(unix))]
+unsafe fn unmap_layout(ptr: *mut u8, layout: Layout) {
+    use std::alloc::GlobalAlloc;
+    unsafe { std::alloc::System.dealloc(ptr, layout) }
+}
@@ -0,0 +1,102 @@
+use std::{cell::Cell, marker::PhantomData};
+
+/// Maximum number of nested regions recorded with each event: deeper regions
+/// are ignored.
+pub const MAX_REGION_DEPTH: usize = 8;
+
+/// The regions a thread is in, outermost first.
+#[derive(Clone, Copy)]
+pub(crate) struct RegionPath {
+    names: [&'static str; MAX_REGION_DEPTH],
+    /// Number of entered regions, including the ones deeper than `MAX_REGION_DEPTH`
+    depth: usize,
+}
+
+impl RegionPath {
+    pub(crate) const EMPTY: RegionPath = RegionPath {
+        names: [""; MAX_REGION_DEPTH],
+        depth: 0,
+    };
+
+    pub(crate) fn names(&self) -> &[&'static str] {
+        &self.names[..self.depth.min(MAX_REGION_DEPTH)]
+    }
+}
+
+thread_local! {
+    // `const` initialized without destructor: accessing it never allocates,
+    // so it is safe to use from inside the global allocator.
+    static REGIONS: Cell<RegionPath> = const { Cell::new(RegionPath::EMPTY) };
+}
+
+/// The regions the current thread is in
+pub(crate) fn current() -> RegionPath {
+    REGIONS.with(Cell::get)
+}
+
+/// Run `f` inside the region `name`: the events it produces carry the region in
+/// [`crate::Allocation::region`], after the regions it's nested in.
+///
+/// ```rust
+/// let index = rallo::region("index", || vec![0_u8; 1024]);
+/// ```
+pub fn region<R, F: FnOnce() -> R>(name: &'static str, f: F) -> R {
+    let _guard = enter_region(name);
+    f()
+}
+
+/// Enter the region `name` until the returned guard is dropped, see [`region`].
+///
+/// The guards of nested regions must be dropped in reverse order.
+pub fn enter_region(name: &'static str) -> RegionGuard {
+    REGIONS.with(|regions| {
+        let mut path = regions.get();
+        if path.depth < MAX_REGION_DEPTH {
+            path.names[path.depth] = name;
+        }
+        path.depth += 1;
+        regions.set(path);
+    });
+    RegionGuard {
+        _not_send: PhantomData,
+    }
+}
+
+/// A region entered by [`enter_region`], left when dropped.
+pub struct RegionGuard {
+    // The region belongs to the thread which entered it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        REGIONS.with(|regions| {
+            let mut path = regions.get();
+            path.depth -= 1;
+            regions.set(path);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_regions() {
+        assert!(current().names().is_empty());
+        region("index", || {
+            assert_eq!(current().names(), ["index"]);
+            let _merge = enter_region("merge");
+            assert_eq!(current().names(), ["index", "merge"]);
+        });
+        assert!(current().names().is_empty());
+
+        let guards: Vec<_> = (0..MAX_REGION_DEPTH + 2)
+            .map(|_| enter_region("deep"))
+            .collect();
+        assert_eq!(current().names().len(), MAX_REGION_DEPTH);
+        drop(guards);
+        assert!(current().names().is_empty());
+    }
+}
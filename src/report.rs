@@ -0,0 +1,290 @@
+//! Text reports of the call sites which allocated: used by the assertions and
+//! the `rallo` command-line tool.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::stats::{Allocation, FrameInfo, Stats};
+
+/// Frames shown for each call site
+const REPORTED_FRAMES: usize = 4;
+
+/// The allocations made from the same place, see [`Stats::call_sites`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallSite {
+    /// The innermost frames outside of rallo and of the standard library,
+    /// innermost first
+    pub frames: Vec<String>,
+    /// Number of allocations (estimated when sampling)
+    pub count: usize,
+    /// Bytes allocated (estimated when sampling)
+    pub bytes: usize,
+}
+
+/// How the allocations of a call site changed between two sessions, see [`Stats::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSiteDiff {
+    /// Frames of the call site, as in [`CallSite::frames`]
+    pub frames: Vec<String>,
+    /// Allocations of the call site in the first session
+    pub before: CallSite,
+    /// Allocations of the call site in the second session
+    pub after: CallSite,
+}
+
+impl CallSiteDiff {
+    /// Bytes allocated by the second session minus the ones of the first
+    pub fn bytes_delta(&self) -> i64 {
+        self.after.bytes as i64 - self.before.bytes as i64
+    }
+
+    /// Allocations of the second session minus the ones of the first
+    pub fn count_delta(&self) -> i64 {
+        self.after.count as i64 - self.before.count as i64
+    }
+}
+
+impl Stats {
+    /// Group the allocations and reallocations by call site, the largest first.
+    ///
+    /// Reallocations count as allocations of their new size.
+    pub fn call_sites(&self) -> Vec<CallSite> {
+        let mut sites: BTreeMap<Vec<String>, CallSite> = BTreeMap::new();
+        for event in self.allocations.iter().chain(&self.reallocations) {
+            let frames = call_site_frames(event);
+            let site = sites.entry(frames.clone()).or_insert_with(|| CallSite {
+                frames,
+                ..CallSite::default()
+            });
+            site.count += event.estimated_count();
+            site.bytes += event.estimated_allocation_size();
+        }
+        let mut sites: Vec<_> = sites.into_values().collect();
+        sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.count.cmp(&a.count)));
+        sites
+    }
+
+    /// Compare the call sites of `self` with the ones of `after`, the largest
+    /// changes in bytes first. The unchanged call sites are left out
+    pub fn diff(&self, after: &Stats) -> Vec<CallSiteDiff> {
+        let before: BTreeMap<_, _> = self
+            .call_sites()
+            .into_iter()
+            .map(|site| (site.frames.clone(), site))
+            .collect();
+        let after: BTreeMap<_, _> = after
+            .call_sites()
+            .into_iter()
+            .map(|site| (site.frames.clone(), site))
+            .collect();
+        let frames: BTreeSet<&Vec<String>> = before.keys().chain(after.keys()).collect();
+
+        let mut diffs: Vec<_> = frames
+            .into_iter()
+            .map(|frames| CallSiteDiff {
+                frames: frames.clone(),
+                before: before.get(frames).cloned().unwrap_or_default(),
+                after: after.get(frames).cloned().unwrap_or_default(),
+            })
+            .filter(|diff| diff.bytes_delta() != 0 || diff.count_delta() != 0)
+            .collect();
+        diffs.sort_by(|a, b| {
+            b.bytes_delta()
+                .abs()
+                .cmp(&a.bytes_delta().abs())
+                .then(b.count_delta().abs().cmp(&a.count_delta().abs()))
+        });
+        diffs
+    }
+}
+
+/// The innermost frames of `event` outside of rallo and of the standard library
+fn call_site_frames(event: &Allocation) -> Vec<String> {
+    // Innermost frame first: the backtrace capture, then rallo, then the
+    // standard library, then the caller
+    let frames: Vec<&FrameInfo> = event.stack.iter().rev().collect();
+    let first_rallo = frames
+        .iter()
+        .position(|frame| is_rallo_frame(frame))
+        .unwrap_or(0);
+    let start = frames[first_rallo..]
+        .iter()
+        .position(|frame| !is_allocator_frame(frame))
+        .map_or(frames.len(), |index| first_rallo + index);
+    frames[start..]
+        .iter()
+        // Stop at the closure given to `track`
+        .take_while(|frame| !is_rallo_frame(frame))
+        .filter(|frame| frame.fn_name.is_some())
+        .take(REPORTED_FRAMES)
+        .map(|frame| format_frame(frame))
+        .collect()
+}
+
+fn filename(frame: &FrameInfo) -> &str {
+    frame
+        .filename
+        .as_ref()
+        .and_then(|f| f.to_str())
+        .unwrap_or_default()
+}
+
+/// Frames of this crate: built from this directory, or from the crates.io
+/// sources when the events come from another program
+fn is_rallo_frame(frame: &FrameInfo) -> bool {
+    let filename = filename(frame);
+    filename.starts_with(concat!(env!("CARGO_MANIFEST_DIR"), "/src/"))
+        || filename.contains(concat!("/rallo-", env!("CARGO_PKG_VERSION"), "/src/"))
+}
+
+/// Frames between the allocation request and rallo: rallo itself, the standard
+/// library and the `__rust_alloc` shims generated for the global allocator
+fn is_allocator_frame(frame: &FrameInfo) -> bool {
+    let filename = filename(frame);
+    let is_shim = frame
+        .fn_name
+        .as_deref()
+        .is_some_and(|name| format!("{:#}", rustc_demangle::demangle(name)).starts_with("__rust"));
+    is_shim
+        || is_rallo_frame(frame)
+        || filename.is_empty()
+        || filename.contains("/rustc/")
+        || filename.contains("/rustlib/")
+}
+
+fn format_frame(frame: &FrameInfo) -> String {
+    let name = frame
+        .fn_name
+        .as_deref()
+        .map(|name| format!("{:#}", rustc_demangle::demangle(name)))
+        .unwrap_or_else(|| "<unknown>".into());
+    let name = if frame.inlined {
+        format!("{name} [inlined]")
+    } else {
+        name
+    };
+    match (&frame.filename, frame.lineno) {
+        (Some(filename), Some(lineno)) => {
+            format!("{name} ({}:{lineno})", filename.display())
+        }
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+    use super::*;
+    use crate::counters::Counters;
+
+    fn frame(name: &str, filename: &str, lineno: u32) -> FrameInfo {
+        FrameInfo {
+            filename: Some(PathBuf::from(filename)),
+            colno: None,
+            lineno: Some(lineno),
+            fn_address: None,
+            fn_name: Some(name.to_string()),
+            inlined: false,
+        }
+    }
+
+    fn allocation(size: usize, caller: &str) -> Allocation {
+        Allocation {
+            allocation_size: size,
+            deallocation_size: 0,
+            address: 0,
+            previous_address: None,
+            weight: 1.0,
+            sequence: 0,
+            timestamp: Duration::ZERO,
+            thread_id: 0,
+            thread_name: None,
+            region: Vec::new(),
+            // Outermost first
+            stack: VecDeque::from([
+                frame("main", "/app/src/main.rs", 1),
+                frame(caller, "/app/src/lib.rs", 10),
+                frame("alloc", "/rustc/library/alloc/src/alloc.rs", 100),
+                frame(
+                    "log_event",
+                    concat!(env!("CARGO_MANIFEST_DIR"), "/src/alloc.rs"),
+                    1,
+                ),
+            ]),
+            truncated: false,
+        }
+    }
+
+    fn stats(allocations: Vec<Allocation>) -> Stats {
+        Stats {
+            allocations: allocations.into(),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
+        }
+    }
+
+    #[test]
+    fn test_call_sites() {
+        let stats = stats(vec![
+            allocation(8, "small"),
+            allocation(1024, "large"),
+            allocation(8, "small"),
+        ]);
+        let sites = stats.call_sites();
+        assert_eq!(
+            sites,
+            vec![
+                CallSite {
+                    frames: vec![
+                        "large (/app/src/lib.rs:10)".to_string(),
+                        "main (/app/src/main.rs:1)".to_string(),
+                    ],
+                    count: 1,
+                    bytes: 1024,
+                },
+                CallSite {
+                    frames: vec![
+                        "small (/app/src/lib.rs:10)".to_string(),
+                        "main (/app/src/main.rs:1)".to_string(),
+                    ],
+                    count: 2,
+                    bytes: 16,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = stats(vec![allocation(1024, "large"), allocation(8, "same")]);
+        let after = stats(vec![
+            allocation(8, "same"),
+            allocation(16, "new"),
+            allocation(16, "new"),
+        ]);
+        let diffs = before.diff(&after);
+
+        let deltas: Vec<_> = diffs
+            .iter()
+            .map(|diff| {
+                (
+                    diff.frames[0].as_str(),
+                    diff.bytes_delta(),
+                    diff.count_delta(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            deltas,
+            vec![
+                ("large (/app/src/lib.rs:10)", -1024, -1),
+                ("new (/app/src/lib.rs:10)", 32, 2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Per-thread state of the byte sampler.
+#[derive(Clone, Copy)]
+struct Sampler {
+    /// xorshift64* state, 0 until the thread samples for the first time
+    rng: u64,
+    /// Bytes left before the next sampled byte
+    bytes_until_sample: i64,
+}
+
+thread_local! {
+    // `const` initialized without destructor: accessing it never allocates,
+    // so it is safe to use from inside the global allocator.
+    static SAMPLER: Cell<Sampler> = const {
+        Cell::new(Sampler {
+            rng: 0,
+            bytes_until_sample: 0,
+        })
+    };
+}
+
+static SEED: AtomicU64 = AtomicU64::new(0x9E37_79B9_7F4A_7C15);
+
+/// Decide whether an event of `size` bytes is sampled, when each byte is sampled
+/// with probability `1 / sampling_rate`.
+///
+/// The distance between two sampled bytes follows an exponential distribution,
+/// so the decision is cheap and doesn't depend on the allocation pattern.
+/// Returns the weight of the sampled event, that is how many events of that size
+/// it stands for.
+pub(crate) fn sample(size: usize, sampling_rate: usize) -> Option<f64> {
+    SAMPLER.with(|cell| {
+        let mut sampler = cell.get();
+        if sampler.rng == 0 {
+            sampler.rng = seed();
+            sampler.bytes_until_sample = next_interval(&mut sampler.rng, sampling_rate);
+        }
+
+        sampler.bytes_until_sample -= size as i64;
+        let weight = if sampler.bytes_until_sample > 0 {
+            None
+        } else {
+            sampler.bytes_until_sample = next_interval(&mut sampler.rng, sampling_rate);
+            Some(weight(size, sampling_rate))
+        };
+
+        cell.set(sampler);
+        weight
+    })
+}
+
+/// Inverse of the probability of an event of `size` bytes to contain a sampled byte.
+fn weight(size: usize, sampling_rate: usize) -> f64 {
+    let probability = 1.0 - (-(size as f64) / sampling_rate as f64).exp();
+    if probability > 0.0 {
+        1.0 / probability
+    } else {
+        1.0
+    }
+}
+
+fn next_interval(rng: &mut u64, sampling_rate: usize) -> i64 {
+    // Uniform in (0, 1]
+    let uniform = ((next_random(rng) >> 11) + 1) as f64 / (1_u64 << 53) as f64;
+    (-uniform.ln() * sampling_rate as f64) as i64 + 1
+}
+
+fn next_random(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+fn seed() -> u64 {
+    // splitmix64 over a global counter: every thread gets a different, non-zero state
+    mix(SEED.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)) | 1
+}
+
+/// The splitmix64 finalizer: spreads the bits of `z` over the whole word
+pub(crate) fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_estimate_is_unbiased() {
+        let sampling_rate = 4096;
+        let size = 64;
+        let count = 100_000;
+
+        let estimated: f64 = (0..count)
+            .filter_map(|_| sample(size, sampling_rate))
+            .map(|weight| weight * size as f64)
+            .sum();
+
+        let expected = (size * count) as f64;
+        let error = (estimated - expected).abs() / expected;
+        assert!(error < 0.1, "estimated {estimated}, expected {expected}");
+    }
+
+    #[test]
+    fn test_sampling_large_events_are_always_sampled() {
+        for _ in 0..1_000 {
+            let weight = sample(1024 * 1024, 16).unwrap();
+            assert!((weight - 1.0).abs() < f64::EPSILON);
+        }
+    }
+}
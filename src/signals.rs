@@ -0,0 +1,312 @@
+//! Control of the tracking from outside the process: SIGUSR1 toggles it,
+//! SIGUSR2 writes a report.
+
+use std::{
+    alloc::GlobalAlloc,
+    ffi::c_void,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use crate::{
+    alloc::{RalloAllocator, StreamingGuard, TrackOptions, TrackingGuard, unrecorded},
+    firefox::FirefoxProfile,
+    stats::Stats,
+};
+
+/// Write end of the pipe waking the helper thread up, -1 until the handlers are installed
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// What SIGUSR2 writes, see [`RalloAllocator::install_signal_handlers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// The flamegraph page of [`crate::Tree::print_flamegraph`]
+    Html,
+    /// A profile for the Firefox Profiler, see [`FirefoxProfile`]
+    Firefox,
+    /// The raw events, see [`crate::Capture`]. The sessions are streamed
+    /// to disk, and SIGUSR2 starts a new file
+    Capture,
+}
+
+impl ReportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Html => "html",
+            ReportFormat::Firefox => "json",
+            ReportFormat::Capture => "bin",
+        }
+    }
+}
+
+impl<A: GlobalAlloc> RalloAllocator<A> {
+    /// Let another process control the tracking with signals: SIGUSR1 starts a
+    /// session with `options`, or stops the running one; SIGUSR2 writes the report
+    /// of the running session, or of the last one, to `dir` in the given format.
+    ///
+    /// The reports are named `rallo-<pid>-<n>.<extension>`, and appear once complete.
+    /// The signal handlers only wake up a helper thread, which does the work.
+    /// Its errors are printed on the standard error.
+    /// Fails if the handlers are already installed.
+    ///
+    /// ```rust,no_run
+    /// use rallo::{RalloAllocator, ReportFormat, TrackOptions};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+    ///
+    /// ALLOCATOR
+    ///     .install_signal_handlers("/tmp/rallo", ReportFormat::Html, TrackOptions::new())
+    ///     .unwrap();
+    /// // kill -USR1 <pid>; kill -USR2 <pid>
+    /// ```
+    pub fn install_signal_handlers<P: Into<PathBuf>>(
+        &'static self,
+        dir: P,
+        format: ReportFormat,
+        options: TrackOptions,
+    ) -> io::Result<()>
+    where
+        A: Sync,
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let [read, write] = fds;
+        if PIPE
+            .compare_exchange(-1, write, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            unsafe {
+                libc::close(read);
+                libc::close(write);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the rallo signal handlers are already installed",
+            ));
+        }
+
+        let helper = std::thread::Builder::new()
+            .name("rallo-signals".into())
+            .spawn(move || {
+                let controller = Controller {
+                    allocator: self,
+                    dir,
+                    format,
+                    options,
+                    session: Session::Idle,
+                    last: None,
+                    reports: 0,
+                };
+                // The reports allocate: don't record them
+                unrecorded(|| controller.run(read))
+            });
+        let helper = match helper {
+            Ok(helper) => helper,
+            Err(e) => {
+                PIPE.store(-1, Ordering::SeqCst);
+                unsafe {
+                    libc::close(read);
+                    libc::close(write);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut installed = Vec::new();
+        for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+            if unsafe { libc::sigaction(signal, &action, &mut previous) } != 0 {
+                let error = io::Error::last_os_error();
+                // Put everything back: the previous handlers, then the pipe,
+                // whose closing stops the helper thread
+                for (signal, previous) in installed {
+                    unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) };
+                }
+                PIPE.store(-1, Ordering::SeqCst);
+                unsafe { libc::close(write) };
+                let _ = helper.join();
+                unsafe { libc::close(read) };
+                return Err(error);
+            }
+            installed.push((signal, previous));
+        }
+        Ok(())
+    }
+}
+
+/// Only async-signal-safe calls here: pass the signal on to the helper thread
+extern "C" fn on_signal(signal: libc::c_int) {
+    // The interrupted code may be about to read `errno`, which `write` can change
+    let errno = errno_location();
+    let saved = if errno.is_null() {
+        0
+    } else {
+        unsafe { *errno }
+    };
+
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const c_void,
+            1,
+        )
+    };
+
+    if !errno.is_null() {
+        unsafe { *errno = saved };
+    }
+}
+
+/// Where the `errno` of the current thread is stored, null if unknown
+fn errno_location() -> *mut libc::c_int {
+    #[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "redox"))]
+    return unsafe { libc::__errno_location() };
+    #[cfg(any(target_os = "android", target_os = "openbsd", target_os = "netbsd"))]
+    return unsafe { libc::__errno() };
+    #[cfg(any(
+        target_vendor = "apple",
+        target_os = "freebsd",
+        target_os = "dragonfly"
+    ))]
+    return unsafe { libc::__error() };
+    #[allow(unreachable_code)]
+    std::ptr::null_mut()
+}
+
+enum Session<A: GlobalAlloc + 'static> {
+    Idle,
+    Tracking(TrackingGuard<'static, A>),
+    /// Streaming to the file at the given path, renamed once finished
+    Streaming(StreamingGuard<File, A>, PathBuf),
+}
+
+/// State of the helper thread
+struct Controller<A: GlobalAlloc + 'static> {
+    allocator: &'static RalloAllocator<A>,
+    dir: PathBuf,
+    format: ReportFormat,
+    options: TrackOptions,
+    session: Session<A>,
+    /// Stats of the last session stopped by SIGUSR1
+    last: Option<Stats>,
+    /// Number of reports written
+    reports: usize,
+}
+
+impl<A: GlobalAlloc + Sync + 'static> Controller<A> {
+    fn run(mut self, pipe: libc::c_int) {
+        loop {
+            let mut signal = 0_u8;
+            let read = unsafe { libc::read(pipe, &mut signal as *mut u8 as *mut c_void, 1) };
+            if read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if read <= 0 {
+                return;
+            }
+
+            let result = match signal as libc::c_int {
+                libc::SIGUSR1 => self.toggle(),
+                libc::SIGUSR2 => self.report(),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                eprintln!("rallo: {e}");
+            }
+        }
+    }
+
+    fn toggle(&mut self) -> io::Result<()> {
+        match std::mem::replace(&mut self.session, Session::Idle) {
+            Session::Idle => self.start(),
+            Session::Tracking(guard) => {
+                self.last = Some(guard.finish().map_err(io::Error::other)?);
+                Ok(())
+            }
+            Session::Streaming(guard, path) => finish_stream(guard, &path),
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.session = if self.format == ReportFormat::Capture {
+            let path = self.next_path();
+            let file = File::create(partial(&path))?;
+            let guard = self
+                .allocator
+                .stream(file, self.options)
+                .map_err(io::Error::other)?;
+            Session::Streaming(guard, path)
+        } else {
+            let guard = self
+                .allocator
+                .start_with(self.options)
+                .map_err(io::Error::other)?;
+            Session::Tracking(guard)
+        };
+        Ok(())
+    }
+
+    fn report(&mut self) -> io::Result<()> {
+        let stats = match &self.session {
+            Session::Streaming(..) => {
+                // Complete the current capture, and go on in a new one
+                self.toggle()?;
+                return self.start();
+            }
+            Session::Tracking(_) => self.allocator.snapshot().map_err(io::Error::other)?,
+            Session::Idle => match &self.last {
+                Some(stats) => stats.clone(),
+                None => return Err(io::Error::other("no session to report")),
+            },
+        };
+
+        let path = self.next_path();
+        match self.format {
+            ReportFormat::Html => {
+                let tree = stats.into_tree().map_err(io::Error::other)?;
+                fs::write(partial(&path), tree.flamegraph_html())?;
+            }
+            ReportFormat::Firefox => {
+                let profile = FirefoxProfile::from_stats(stats).map_err(io::Error::other)?;
+                profile.write_json(partial(&path))?;
+            }
+            ReportFormat::Capture => unreachable!("captures are streamed"),
+        }
+        fs::rename(partial(&path), path)
+    }
+
+    fn next_path(&mut self) -> PathBuf {
+        self.reports += 1;
+        self.dir.join(format!(
+            "rallo-{}-{}.{}",
+            std::process::id(),
+            self.reports,
+            self.format.extension()
+        ))
+    }
+}
+
+fn finish_stream<A: GlobalAlloc>(guard: StreamingGuard<File, A>, path: &Path) -> io::Result<()> {
+    guard.finish()?;
+    fs::rename(partial(path), path)
+}
+
+/// Where the report at `path` is written until it is complete
+fn partial(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    partial.into()
+}
@@ -0,0 +1,357 @@
+//! Interning of the stacks captured for the events: each distinct stack is
+//! stored once, in a lock-free table shared by the recording threads, and the
+//! events only keep its identifier.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mmap;
+
+/// Frames gathered before being interned. Stacks are stored as chains of chunks
+/// of up to `CHUNK` frames, the innermost first: their depth is not bounded by
+/// the buffer kept on the call stack, and stacks sharing their innermost frames
+/// share chunks.
+const CHUNK: usize = 32;
+
+/// Words of a chunk before its frames: hash, parent chunk, then length and flags
+const HEADER: usize = 3;
+
+/// Parent of the innermost chunk of a stack
+const NO_PARENT: usize = usize::MAX;
+
+/// Flag of the outermost chunk of a truncated stack, next to the length
+const TRUNCATED: usize = 1 << (usize::BITS - 1);
+
+/// Identifier of an interned stack: position of its outermost chunk in the table
+pub(crate) type StackId = usize;
+
+/// Open-addressing hash table of the stack chunks, mapped directly from the OS.
+///
+/// Chunks are written once, before being published in a slot, and never
+/// modified until the table is reset: reading an interned stack needs no lock.
+pub(crate) struct StackTable {
+    /// `id + 1` of the chunk stored in each slot, 0 for the free ones.
+    /// Their number is a power of two
+    slots: &'static [AtomicUsize],
+    /// The chunks, one after the other
+    words: &'static [AtomicUsize],
+    /// Words of `words` already reserved
+    used: AtomicUsize,
+}
+
+impl StackTable {
+    pub(crate) const fn new() -> Self {
+        StackTable {
+            slots: &[],
+            words: &[],
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Map a table for `max_frames` frames, including the headers of the chunks.
+    /// It doesn't come from the global allocator: it never shows up in the stats.
+    pub(crate) fn allocate(max_frames: usize) -> Self {
+        // Every chunk takes at least `HEADER` words: the slots are never more
+        // than two thirds full
+        let slot_count = (max_frames / 2).max(1).next_power_of_two();
+        // Safety: zeroed memory is a valid `AtomicUsize`, and the mappings are
+        // only freed by `free`
+        unsafe {
+            StackTable {
+                slots: std::slice::from_raw_parts(mmap::map(slot_count), slot_count),
+                words: std::slice::from_raw_parts(mmap::map(max_frames), max_frames),
+                used: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    /// Free the buffers returned by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// The table must come from `allocate` and must not be used anymore.
+    pub(crate) unsafe fn free(&self) {
+        unsafe {
+            mmap::unmap(self.slots.as_ptr() as *mut AtomicUsize, self.slots.len());
+            mmap::unmap(self.words.as_ptr() as *mut AtomicUsize, self.words.len());
+        }
+    }
+
+    /// Forget the stacks of the previous session.
+    /// No stack can be interned concurrently.
+    pub(crate) fn reset(&self) {
+        if self.used.swap(0, Ordering::SeqCst) != 0 {
+            for slot in self.slots {
+                slot.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Words used by the stacks interned so far
+    #[cfg(test)]
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Start interning a stack, frame by frame
+    pub(crate) fn builder(&self) -> StackBuilder<'_> {
+        StackBuilder {
+            table: self,
+            frames: [0; CHUNK],
+            len: 0,
+            parent: Some(NO_PARENT),
+        }
+    }
+
+    /// The frames of the stack `id`, innermost first, and whether it was truncated.
+    ///
+    /// The identifier can come from an event read while another thread overwrites
+    /// it: garbage gives a wrong stack, never an access out of the table.
+    pub(crate) fn frames(&self, id: StackId) -> (Vec<usize>, bool) {
+        let mut chunks = Vec::new();
+        let mut truncated = false;
+        let mut id = id;
+        while let Some([_, parent, info]) = self.header(id) {
+            if chunks.is_empty() {
+                truncated = info & TRUNCATED != 0;
+            }
+            chunks.push((id, info & !TRUNCATED));
+            // The parents are always reserved before their children
+            if parent >= id {
+                break;
+            }
+            id = parent;
+        }
+
+        let mut frames = Vec::new();
+        for &(id, len) in chunks.iter().rev() {
+            let start = id + HEADER;
+            frames.extend(
+                self.words[start..start + len]
+                    .iter()
+                    .map(|word| word.load(Ordering::Relaxed)),
+            );
+        }
+        (frames, truncated)
+    }
+
+    /// Header of the chunk `id`, if it is a plausible one
+    fn header(&self, id: StackId) -> Option<[usize; HEADER]> {
+        let header = self.words.get(id..id.checked_add(HEADER)?)?;
+        let [hash, parent, info] = [0, 1, 2].map(|i| header[i].load(Ordering::Relaxed));
+        let len = info & !TRUNCATED;
+        (len <= CHUNK && id + HEADER + len <= self.words.len()).then_some([hash, parent, info])
+    }
+
+    /// Identifier of the chunk of `frames` following `parent`, interning it if
+    /// new. `None` if the table is full.
+    fn intern(&self, parent: usize, frames: &[usize], truncated: bool) -> Option<StackId> {
+        let info = frames.len() | if truncated { TRUNCATED } else { 0 };
+        let hash = hash(parent, info, frames);
+        let mask = self.slots.len().checked_sub(1)?;
+        // Chunk written by this thread, not published yet
+        let mut reserved = None;
+
+        for probe in 0..self.slots.len() {
+            let slot = &self.slots[hash.wrapping_add(probe) & mask];
+            let mut current = slot.load(Ordering::Acquire);
+            if current == 0 {
+                let id = match reserved {
+                    Some(id) => id,
+                    None => self.write(hash, parent, info, frames)?,
+                };
+                reserved = Some(id);
+                match slot.compare_exchange(0, id + 1, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => return Some(id),
+                    // Another thread published a chunk in the slot first
+                    Err(actual) => current = actual,
+                }
+            }
+            if self.matches(current - 1, hash, parent, info, frames) {
+                return Some(current - 1);
+            }
+        }
+        None
+    }
+
+    /// Write a new chunk at the end of the table, returning its identifier
+    fn write(&self, hash: usize, parent: usize, info: usize, frames: &[usize]) -> Option<StackId> {
+        let size = HEADER + frames.len();
+        let mut id = self.used.load(Ordering::Relaxed);
+        loop {
+            if id + size > self.words.len() {
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                id,
+                id + size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => id = actual,
+            }
+        }
+
+        let words = [hash, parent, info]
+            .into_iter()
+            .chain(frames.iter().copied());
+        for (slot, word) in self.words[id..id + size].iter().zip(words) {
+            slot.store(word, Ordering::Relaxed);
+        }
+        Some(id)
+    }
+
+    fn matches(
+        &self,
+        id: StackId,
+        hash: usize,
+        parent: usize,
+        info: usize,
+        frames: &[usize],
+    ) -> bool {
+        self.header(id) == Some([hash, parent, info])
+            && self.words[id + HEADER..id + HEADER + frames.len()]
+                .iter()
+                .zip(frames)
+                .all(|(word, &frame)| word.load(Ordering::Relaxed) == frame)
+    }
+}
+
+fn hash(parent: usize, info: usize, frames: &[usize]) -> usize {
+    // FxHash: fast, and good enough for instruction pointers
+    const SEED: usize = 0x517c_c1b7_2722_0a95_u64 as usize;
+    [parent, info]
+        .iter()
+        .chain(frames)
+        .fold(0, |hash: usize, &word| {
+            (hash.rotate_left(5) ^ word).wrapping_mul(SEED)
+        })
+}
+
+/// A stack being interned, see [`StackTable::builder`]. Never allocates: it
+/// can be used from inside the global allocator.
+pub(crate) struct StackBuilder<'a> {
+    table: &'a StackTable,
+    /// Frames of the current chunk
+    frames: [usize; CHUNK],
+    len: usize,
+    /// Previous chunk, `None` once the table is full
+    parent: Option<usize>,
+}
+
+impl StackBuilder<'_> {
+    /// Add the next frame, outer than the previous ones
+    pub(crate) fn push(&mut self, ip: usize) {
+        if self.len == CHUNK {
+            self.parent = self
+                .parent
+                .and_then(|parent| self.table.intern(parent, &self.frames, false));
+            self.len = 0;
+        }
+        self.frames[self.len] = ip;
+        self.len += 1;
+    }
+
+    /// Identifier of the stack, `None` if it didn't fit in the table
+    pub(crate) fn finish(self, truncated: bool) -> Option<StackId> {
+        let parent = self.parent?;
+        self.table
+            .intern(parent, &self.frames[..self.len], truncated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intern(table: &StackTable, frames: &[usize], truncated: bool) -> Option<StackId> {
+        let mut builder = table.builder();
+        for &ip in frames {
+            builder.push(ip);
+        }
+        builder.finish(truncated)
+    }
+
+    #[test]
+    fn test_intern() {
+        let table = StackTable::allocate(1024);
+        let long: Vec<usize> = (1..=100).collect();
+        let shared: Vec<usize> = (1..=40).chain([1000]).collect();
+
+        let stacks = [
+            (vec![], false),
+            (vec![1, 2, 3], false),
+            (vec![1, 2, 3], true),
+            (long.clone(), false),
+            (long[..CHUNK].to_vec(), false),
+            (shared.clone(), true),
+        ];
+        let ids: Vec<_> = stacks
+            .iter()
+            .map(|(frames, truncated)| intern(&table, frames, *truncated).unwrap())
+            .collect();
+        for ((frames, truncated), &id) in stacks.iter().zip(&ids) {
+            assert_eq!(table.frames(id), (frames.clone(), *truncated));
+        }
+
+        // Interned again, the stacks keep their identifier and take no room
+        let used = table.used();
+        for ((frames, truncated), &id) in stacks.iter().zip(&ids) {
+            assert_eq!(intern(&table, frames, *truncated), Some(id));
+        }
+        assert_eq!(table.used(), used);
+        // `long[..CHUNK]` and the innermost chunk of `shared` are the one of `long`
+        assert_eq!(used, 8 * HEADER + 3 + 3 + 100 + (shared.len() - CHUNK));
+
+        unsafe { table.free() };
+    }
+
+    #[test]
+    fn test_full_table() {
+        let table = StackTable::allocate(HEADER + CHUNK + HEADER + 1);
+        let long: Vec<usize> = (1..=CHUNK + 1).collect();
+        assert!(intern(&table, &long, false).is_some());
+        assert!(intern(&table, &[7, 8], false).is_none());
+        assert!(intern(&table, &[7], false).is_none());
+
+        // The stacks already interned, and their chunks, are still found
+        assert!(intern(&table, &long, false).is_some());
+        assert!(intern(&table, &long[..CHUNK], false).is_some());
+        assert!(intern(&table, &long[..CHUNK - 1], false).is_none());
+
+        table.reset();
+        assert_eq!(table.used(), 0);
+        let id = intern(&table, &[7, 8], false).unwrap();
+        assert_eq!(table.frames(id), (vec![7, 8], false));
+        unsafe { table.free() };
+    }
+
+    #[test]
+    fn test_concurrent_intern() {
+        let table = StackTable::allocate(1 << 16);
+        let stacks: Vec<Vec<usize>> = (0..64)
+            .map(|i| (0..i + 1).map(|frame| frame * 64 + i % 8).collect())
+            .collect();
+        let ids: Vec<Vec<StackId>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        stacks
+                            .iter()
+                            .map(|frames| intern(&table, frames, false).unwrap())
+                            .collect()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        for thread_ids in &ids {
+            assert_eq!(thread_ids, &ids[0]);
+        }
+        for (frames, &id) in stacks.iter().zip(&ids[0]) {
+            assert_eq!(table.frames(id), (frames.clone(), false));
+        }
+        unsafe { table.free() };
+    }
+}
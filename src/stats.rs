@@ -1,8 +1,19 @@
-use std::{borrow::Cow, collections::VecDeque, ffi::c_void, fmt::Debug, io::BufRead, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ffi::c_void,
+    fmt::Debug,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter},
+    path::Path,
+    time::Duration,
+};
 
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Clone)]
+use crate::counters::Counters;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FrameInfo {
     /// Filename where the function call was made
     pub filename: Option<std::path::PathBuf>,
@@ -11,12 +22,17 @@ pub struct FrameInfo {
     /// Line number where the function call was made
     pub lineno: Option<u32>,
     /// Address of the function
+    #[serde(with = "fn_address")]
     pub fn_address: Option<*mut c_void>,
     /// Name of the function
     pub fn_name: Option<String>,
+    /// Whether the call was inlined by the compiler: the function of this frame
+    /// was compiled inside the one of its caller, the previous frame
+    #[serde(default)]
+    pub inlined: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Allocation {
     /// Allocation size
     pub allocation_size: usize,
@@ -24,21 +40,347 @@ pub struct Allocation {
     pub deallocation_size: usize,
     /// address of the allocation
     pub address: usize,
+    /// Address the memory was moved from. Only set for reallocations
+    pub previous_address: Option<usize>,
+    /// How many events this one stands for: `1.0` unless sampling
+    pub weight: f64,
+    /// Position of the event among all the events of the session,
+    /// whatever their kind
+    pub sequence: u64,
+    /// Time elapsed between the start of the session and the event
+    pub timestamp: Duration,
+    /// Identifier of the thread which made the call: the OS thread id where available
+    pub thread_id: u64,
+    /// Name of the thread which made the call, if any
+    pub thread_name: Option<String>,
+    /// Regions the thread was in, outermost first, see [`crate::region`]
+    pub region: Vec<String>,
     /// Stack trace
     pub stack: VecDeque<FrameInfo>,
+    /// Whether the outermost frames of the stack were left out, see
+    /// [`crate::TrackOptions::max_frame_length`] and
+    /// [`crate::TrackOptions::max_stack_frames`]
+    #[serde(default)]
+    pub truncated: bool,
 }
 
-#[derive(Debug)]
+impl Allocation {
+    /// Estimated number of allocated bytes this event stands for
+    pub fn estimated_allocation_size(&self) -> usize {
+        (self.allocation_size as f64 * self.weight).round() as usize
+    }
+
+    /// Estimated number of deallocated bytes this event stands for
+    pub fn estimated_deallocation_size(&self) -> usize {
+        (self.deallocation_size as f64 * self.weight).round() as usize
+    }
+
+    /// Estimated number of events this event stands for
+    pub fn estimated_count(&self) -> usize {
+        self.weight.round() as usize
+    }
+}
+
+/// The stats can be saved with [`Stats::write_json`], to be loaded back by
+/// [`Stats::read_json`] or the `rallo` command-line tool without symbolizing again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stats {
     /// Allocations
     pub allocations: VecDeque<Allocation>,
     /// Deallocations
     pub deallocations: VecDeque<Allocation>,
+    /// Reallocations: `deallocation_size` is the old size and `allocation_size` the new one
+    pub reallocations: VecDeque<Allocation>,
+    /// Allocations and reallocations failed by [`crate::TrackOptions::fail_allocations`]:
+    /// `allocation_size` is the requested size, `previous_address` the block a
+    /// reallocation failed to move
+    pub failed_allocations: VecDeque<Allocation>,
+    /// Number of events lost because the log buffers were full.
+    /// If it isn't 0, the stats are incomplete
+    pub dropped_events: usize,
+    /// One byte every `sampling_rate` was sampled, if set.
+    /// In that case, the sizes are estimated through [`Allocation::weight`]
+    pub sampling_rate: Option<usize>,
+    /// Exact totals of the session, not affected by sampling nor by dropped events
+    pub counters: Counters,
+    /// Set on the stats returned by [`Stats::into_peak`]
+    pub peak: Option<PeakInfo>,
+}
+
+/// When the memory held by the recorded events peaked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeakInfo {
+    /// Bytes held by the recorded events at the peak (estimated when sampling)
+    pub live_bytes: usize,
+    /// Sequence number of the event which reached the peak
+    pub sequence: u64,
+    /// Time elapsed between the start of the session and the peak
+    pub timestamp: Duration,
+}
+
+/// Kind of a recorded event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Allocation,
+    Deallocation,
+    Reallocation,
+}
+
+/// (De)serialize the function addresses as integers
+mod fn_address {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        address: &Option<*mut c_void>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        address
+            .map(|address| address as usize)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<*mut c_void>, D::Error> {
+        let address = Option::<usize>::deserialize(deserializer)?;
+        Ok(address.map(|address| address as *mut c_void))
+    }
 }
 
 impl Stats {
+    /// Save the stats as JSON to `path`
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self).map_err(io::Error::other)
+    }
+
+    /// Load the stats saved by [`Stats::write_json`]
+    pub fn read_json<P: AsRef<Path>>(path: P) -> io::Result<Stats> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(io::Error::other)
+    }
+
+    /// Every event, in the order they happened
+    pub fn timeline(&self) -> Vec<(EventKind, &Allocation)> {
+        let mut timeline: Vec<_> = self
+            .allocations
+            .iter()
+            .map(|event| (EventKind::Allocation, event))
+            .chain(
+                self.deallocations
+                    .iter()
+                    .map(|event| (EventKind::Deallocation, event)),
+            )
+            .chain(
+                self.reallocations
+                    .iter()
+                    .map(|event| (EventKind::Reallocation, event)),
+            )
+            .collect();
+        timeline.sort_by_key(|(_, event)| event.sequence);
+        timeline
+    }
+
+    /// Consume the stats returning every event, in the order they happened
+    pub fn into_timeline(self) -> Vec<(EventKind, Allocation)> {
+        let mut timeline: Vec<_> = self
+            .allocations
+            .into_iter()
+            .map(|event| (EventKind::Allocation, event))
+            .chain(
+                self.deallocations
+                    .into_iter()
+                    .map(|event| (EventKind::Deallocation, event)),
+            )
+            .chain(
+                self.reallocations
+                    .into_iter()
+                    .map(|event| (EventKind::Reallocation, event)),
+            )
+            .collect();
+        timeline.sort_by_key(|(_, event)| event.sequence);
+        timeline
+    }
+
+    /// Threads which produced at least one event, with their names
+    pub fn threads(&self) -> BTreeMap<u64, Option<String>> {
+        self.allocations
+            .iter()
+            .chain(&self.deallocations)
+            .chain(&self.reallocations)
+            .chain(&self.failed_allocations)
+            .map(|event| (event.thread_id, event.thread_name.clone()))
+            .collect()
+    }
+
+    /// Keep only the events produced by the thread `thread_id`
+    pub fn filter_by_thread(mut self, thread_id: u64) -> Stats {
+        self.allocations
+            .retain(|event| event.thread_id == thread_id);
+        self.deallocations
+            .retain(|event| event.thread_id == thread_id);
+        self.reallocations
+            .retain(|event| event.thread_id == thread_id);
+        self.failed_allocations
+            .retain(|event| event.thread_id == thread_id);
+        self
+    }
+
+    /// Region paths which produced at least one event, see [`crate::region`].
+    /// The events outside of any region have an empty path
+    pub fn regions(&self) -> BTreeSet<Vec<String>> {
+        self.allocations
+            .iter()
+            .chain(&self.deallocations)
+            .chain(&self.reallocations)
+            .chain(&self.failed_allocations)
+            .map(|event| event.region.clone())
+            .collect()
+    }
+
+    /// Keep only the events produced inside the region `name`, at any depth
+    pub fn filter_by_region(mut self, name: &str) -> Stats {
+        self.allocations
+            .retain(|event| event.region.iter().any(|region| region == name));
+        self.deallocations
+            .retain(|event| event.region.iter().any(|region| region == name));
+        self.reallocations
+            .retain(|event| event.region.iter().any(|region| region == name));
+        self.failed_allocations
+            .retain(|event| event.region.iter().any(|region| region == name));
+        self
+    }
+
+    /// Split the events by region path, see [`Stats::regions`].
+    /// The other fields, like [`Stats::counters`], are copied from the session
+    pub fn group_by_region(self) -> BTreeMap<Vec<String>, Stats> {
+        let mut groups: BTreeMap<Vec<String>, Stats> = BTreeMap::new();
+        let Stats {
+            allocations,
+            deallocations,
+            reallocations,
+            failed_allocations,
+            dropped_events,
+            sampling_rate,
+            counters,
+            peak,
+        } = self;
+
+        let empty = || Stats {
+            allocations: VecDeque::new(),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events,
+            sampling_rate,
+            counters,
+            peak,
+        };
+
+        for event in allocations {
+            let group = groups.entry(event.region.clone()).or_insert_with(empty);
+            group.allocations.push_back(event);
+        }
+        for event in deallocations {
+            let group = groups.entry(event.region.clone()).or_insert_with(empty);
+            group.deallocations.push_back(event);
+        }
+        for event in reallocations {
+            let group = groups.entry(event.region.clone()).or_insert_with(empty);
+            group.reallocations.push_back(event);
+        }
+        for event in failed_allocations {
+            let group = groups.entry(event.region.clone()).or_insert_with(empty);
+            group.failed_allocations.push_back(event);
+        }
+
+        groups
+    }
+
+    /// Keep only the allocations which were live when the memory held by the
+    /// session peaked, matching allocations and deallocations by address.
+    ///
+    /// A block moved by reallocations is reported with its size at the peak, under
+    /// the call site of its last move. Blocks allocated before the session aren't
+    /// known, so freeing them doesn't lower the usage.
+    /// The result can be turned into a tree or a Firefox profile as any other stats.
+    ///
+    /// Fails on sampled stats: the deallocations are sampled independently of
+    /// their allocations, so the live blocks can't be known.
+    pub fn into_peak(mut self) -> Result<Stats, Cow<'static, str>> {
+        if self.sampling_rate.is_some() {
+            return Err("the peak can't be computed from sampled stats".into());
+        }
+        let dropped_events = self.dropped_events;
+        let failed_allocations = std::mem::take(&mut self.failed_allocations);
+        let sampling_rate = self.sampling_rate;
+        let counters = self.counters;
+        let timeline = self.into_timeline();
+
+        let mut peak_bytes = 0;
+        let mut peak_end = 0;
+        replay_live(&timeline, |index, live_bytes| {
+            if live_bytes > peak_bytes {
+                peak_bytes = live_bytes;
+                peak_end = index + 1;
+            }
+        });
+
+        let mut live: Vec<usize> = replay_live(&timeline[..peak_end], |_, _| {})
+            .into_values()
+            .map(|(index, _)| index)
+            .collect();
+        // Newest first, as in the collected stats
+        live.sort_unstable_by(|a, b| b.cmp(a));
+
+        let peak = timeline
+            .get(peak_end.wrapping_sub(1))
+            .map(|(_, event)| PeakInfo {
+                live_bytes: peak_bytes,
+                sequence: event.sequence,
+                timestamp: event.timestamp,
+            })
+            .unwrap_or(PeakInfo {
+                live_bytes: 0,
+                sequence: 0,
+                timestamp: Duration::ZERO,
+            });
+
+        let mut timeline: Vec<_> = timeline.into_iter().map(Some).collect();
+        let allocations = live
+            .into_iter()
+            .filter_map(|index| timeline[index].take())
+            .map(|(_, mut event)| {
+                event.deallocation_size = 0;
+                event.previous_address = None;
+                event
+            })
+            .collect();
+
+        Ok(Stats {
+            allocations,
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations,
+            dropped_events,
+            sampling_rate,
+            counters,
+            peak: Some(peak),
+        })
+    }
+
     /// Transform the raw stats into a tree structure
     pub fn into_tree(self) -> Result<Tree<Key>, Cow<'static, str>> {
+        self.build_tree(false)
+    }
+
+    /// Same as [`Stats::into_tree`], with the regions of the events as the first
+    /// levels of the tree, see [`crate::region`]
+    pub fn into_tree_by_region(self) -> Result<Tree<Key>, Cow<'static, str>> {
+        self.build_tree(true)
+    }
+
+    fn build_tree(self, by_region: bool) -> Result<Tree<Key>, Cow<'static, str>> {
         let cwd = std::env::current_dir()
             .map_err(|e| format!("failed to get current directory: {e:?}"))?;
         let cwd = cwd.to_str().ok_or("current directory is not valid UTF-8")?;
@@ -52,86 +394,55 @@ impl Stats {
                 fn_address: std::ptr::null_mut(),
                 fn_name: "<root>".to_string(),
                 file_content: None,
+                inlined: false,
             },
             allocation: 0,
             allocation_count: 0,
             deallocation: 0,
             deallocation_count: 0,
+            reallocation: 0,
+            reallocation_count: 0,
+            capture: Some(CaptureInfo {
+                dropped_events: self.dropped_events,
+                sampling_rate: self.sampling_rate,
+                peak: self.peak,
+            }),
             children: Vec::new(),
         };
 
         for allocation in self.allocations {
-            let mut pointer = &mut root;
-
-            let stack_len = allocation.stack.len();
-            for (index, info) in allocation.stack.into_iter().enumerate() {
-                let is_last = stack_len == index + 1;
-                let key: Key = match info.try_into() {
-                    Ok(key) => key,
-                    Err(_) => continue,
-                };
-
-                let found = pointer.children.iter().position(|c| c.key == key);
-                pointer = if let Some(found) = found {
-                    pointer.children.get_mut(found).unwrap()
-                } else {
-                    let c = Tree {
-                        category: guess_category(cwd, key.filename.as_str()),
-                        key,
-                        allocation: 0,
-                        allocation_count: 0,
-                        deallocation: 0,
-                        deallocation_count: 0,
-                        children: Vec::new(),
-                    };
-                    pointer.children.push(c);
-                    pointer.children.last_mut().unwrap()
-                };
-
-                // Put the effort only on the last frame
-                if is_last {
-                    pointer.allocation += allocation.allocation_size;
-                    pointer.deallocation += allocation.deallocation_size;
-                    pointer.allocation_count += 1;
-                }
-            }
+            add_to_tree(&mut root, cwd, by_region, allocation, |node, allocation| {
+                node.allocation += allocation.estimated_allocation_size();
+                node.deallocation += allocation.estimated_deallocation_size();
+                node.allocation_count += allocation.estimated_count();
+            });
         }
 
         for deallocation in self.deallocations {
-            let mut pointer = &mut root;
-
-            let stack_len = deallocation.stack.len();
-            for (index, info) in deallocation.stack.into_iter().enumerate() {
-                let is_last = stack_len == index + 1;
-                let key: Key = match info.try_into() {
-                    Ok(key) => key,
-                    Err(_) => continue,
-                };
-
-                let found = pointer.children.iter().position(|c| c.key == key);
-                pointer = if let Some(found) = found {
-                    pointer.children.get_mut(found).unwrap()
-                } else {
-                    let c = Tree {
-                        category: guess_category(cwd, key.filename.as_str()),
-                        key,
-                        allocation: 0,
-                        allocation_count: 0,
-                        deallocation: 0,
-                        deallocation_count: 0,
-                        children: Vec::new(),
-                    };
-                    pointer.children.push(c);
-                    pointer.children.last_mut().unwrap()
-                };
+            add_to_tree(
+                &mut root,
+                cwd,
+                by_region,
+                deallocation,
+                |node, deallocation| {
+                    node.allocation += deallocation.estimated_allocation_size();
+                    node.deallocation += deallocation.estimated_deallocation_size();
+                    node.deallocation_count += deallocation.estimated_count();
+                },
+            );
+        }
 
-                // Put the effort only on the last frame
-                if is_last {
-                    pointer.allocation += deallocation.allocation_size;
-                    pointer.deallocation += deallocation.deallocation_size;
-                    pointer.deallocation_count += 1;
-                }
-            }
+        for reallocation in self.reallocations {
+            add_to_tree(
+                &mut root,
+                cwd,
+                by_region,
+                reallocation,
+                |node, reallocation| {
+                    node.reallocation += reallocation.estimated_allocation_size();
+                    node.reallocation_count += reallocation.estimated_count();
+                },
+            );
         }
 
         root.update_value();
@@ -140,6 +451,103 @@ impl Stats {
     }
 }
 
+/// Replay `timeline`, returning the blocks still live at its end by address,
+/// with the position of the event which produced them and their estimated size.
+/// `on_event` receives the position of each event and the bytes live after it.
+fn replay_live<F>(
+    timeline: &[(EventKind, Allocation)],
+    mut on_event: F,
+) -> HashMap<usize, (usize, usize)>
+where
+    F: FnMut(usize, usize),
+{
+    let mut live: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut live_bytes = 0;
+    for (index, (kind, event)) in timeline.iter().enumerate() {
+        let freed = match kind {
+            EventKind::Allocation => None,
+            EventKind::Deallocation => Some(event.address),
+            EventKind::Reallocation => event.previous_address,
+        };
+        if let Some((_, size)) = freed.and_then(|address| live.remove(&address)) {
+            live_bytes -= size;
+        }
+        if *kind != EventKind::Deallocation {
+            let size = event.estimated_allocation_size();
+            live.insert(event.address, (index, size));
+            live_bytes += size;
+        }
+        on_event(index, live_bytes);
+    }
+    live
+}
+
+/// Walk `allocation`'s stack from `root`, creating the missing nodes, and let
+/// `update` account the event on the node of the last frame.
+/// With `by_region`, the regions of the event come before the stack.
+fn add_to_tree<F>(
+    root: &mut Tree<Key>,
+    cwd: &str,
+    by_region: bool,
+    mut allocation: Allocation,
+    update: F,
+) where
+    F: Fn(&mut Tree<Key>, &Allocation),
+{
+    let mut pointer = root;
+
+    if by_region {
+        for name in &allocation.region {
+            pointer = child(pointer, Key::region(name), Category::Region);
+        }
+    }
+    // The outermost frames are missing: don't mix the stack with the complete ones
+    if allocation.truncated {
+        pointer = child(pointer, Key::truncated(), Category::Unknown);
+    }
+
+    let stack = std::mem::take(&mut allocation.stack);
+    let stack_len = stack.len();
+    for (index, info) in stack.into_iter().enumerate() {
+        let is_last = stack_len == index + 1;
+        let key: Key = match info.try_into() {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        let category = guess_category(cwd, key.filename.as_str());
+        pointer = child(pointer, key, category);
+
+        // Put the effort only on the last frame
+        if is_last {
+            update(pointer, &allocation);
+        }
+    }
+}
+
+/// The child of `node` with `key`, created if missing
+fn child(node: &mut Tree<Key>, key: Key, category: Category) -> &mut Tree<Key> {
+    let found = node.children.iter().position(|c| c.key == key);
+    if let Some(found) = found {
+        node.children.get_mut(found).unwrap()
+    } else {
+        let c = Tree {
+            category,
+            key,
+            allocation: 0,
+            allocation_count: 0,
+            deallocation: 0,
+            deallocation_count: 0,
+            reallocation: 0,
+            reallocation_count: 0,
+            capture: None,
+            children: Vec::new(),
+        };
+        node.children.push(c);
+        node.children.last_mut().unwrap()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Clone)]
 pub struct FileContent {
     pub before: Vec<String>,
@@ -156,6 +564,36 @@ pub struct Key {
     pub fn_address: *mut c_void,
     pub fn_name: String,
     pub file_content: Option<FileContent>,
+    /// See [`FrameInfo::inlined`]
+    pub inlined: bool,
+}
+
+impl Key {
+    /// Node of the tree standing for the region `name`
+    fn region(name: &str) -> Key {
+        Key {
+            filename: "<region>".to_string(),
+            colno: 0,
+            lineno: 0,
+            fn_address: std::ptr::null_mut(),
+            fn_name: name.to_string(),
+            file_content: None,
+            inlined: false,
+        }
+    }
+
+    /// Node of the tree standing for the frames left out of truncated stacks
+    fn truncated() -> Key {
+        Key {
+            filename: "<truncated>".to_string(),
+            colno: 0,
+            lineno: 0,
+            fn_address: std::ptr::null_mut(),
+            fn_name: "<truncated>".to_string(),
+            file_content: None,
+            inlined: false,
+        }
+    }
 }
 
 impl TryFrom<FrameInfo> for Key {
@@ -215,6 +653,7 @@ impl TryFrom<FrameInfo> for Key {
                 fn_address,
                 fn_name,
                 file_content,
+                inlined: value.inlined,
             })
         } else {
             Ok(Key {
@@ -224,6 +663,7 @@ impl TryFrom<FrameInfo> for Key {
                 fn_address: std::ptr::null_mut(),
                 fn_name: "<unknown>".to_string(),
                 file_content: None,
+                inlined: false,
             })
         }
     }
@@ -238,20 +678,49 @@ pub struct Tree<K: Debug + Serialize> {
     pub allocation_count: usize,
     pub deallocation: usize,
     pub deallocation_count: usize,
+    pub reallocation: usize,
+    pub reallocation_count: usize,
     pub category: Category,
+    /// How the events were collected. Set on the root only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture: Option<CaptureInfo>,
     pub children: Vec<Tree<K>>,
 }
 
+#[derive(Debug, Default, Clone, Serialize)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+/// Information on how the events of a report were collected
+pub struct CaptureInfo {
+    /// Number of events lost because the log buffers were full
+    pub dropped_events: usize,
+    /// One byte every `sampling_rate` was sampled, if set: the values are estimates
+    pub sampling_rate: Option<usize>,
+    /// Set when the tree shows the allocations live at the peak
+    pub peak: Option<PeakInfo>,
+}
+
+impl CaptureInfo {
+    /// Whether every event was recorded
+    pub fn is_complete(&self) -> bool {
+        self.dropped_events == 0
+    }
+}
+
 impl<K: Debug + Serialize> Tree<K> {
     /// Write an HTML file with the flamegraph at the given path
     pub fn print_flamegraph<P>(&self, path: P)
     where
         P: AsRef<Path>,
     {
+        std::fs::write(path, self.flamegraph_html()).unwrap();
+    }
+
+    /// The page written by [`Tree::print_flamegraph`], to write it elsewhere
+    /// or to handle the errors
+    pub fn flamegraph_html(&self) -> String {
         let d = serde_json::to_string(&self).unwrap();
         let html = include_str!("../template.html");
-        let html = html.replace("{ undefined }", &d);
-        std::fs::write(path, html).unwrap();
+        html.replace("{ undefined }", &d)
     }
 
     fn update_value(&mut self) {
@@ -259,6 +728,8 @@ impl<K: Debug + Serialize> Tree<K> {
         let mut allocation_count = 0;
         let mut deallocation = 0;
         let mut deallocation_count = 0;
+        let mut reallocation = 0;
+        let mut reallocation_count = 0;
         for child in &mut self.children {
             child.update_value();
             allocation += child.allocation;
@@ -269,11 +740,17 @@ impl<K: Debug + Serialize> Tree<K> {
             if child.deallocation > 0 {
                 deallocation_count += child.deallocation_count;
             }
+            reallocation += child.reallocation;
+            if child.reallocation > 0 {
+                reallocation_count += child.reallocation_count;
+            }
         }
         self.allocation += allocation;
         self.allocation_count += allocation_count;
         self.deallocation += deallocation;
         self.deallocation_count += deallocation_count;
+        self.reallocation += reallocation;
+        self.reallocation_count += reallocation_count;
     }
 }
 
@@ -286,6 +763,7 @@ impl<K: Debug + Serialize> Tree<K> {
 /// - `deps`: Dependencies
 /// - `application`: Application code
 /// - `unknown`: Unknown code
+/// - `region`: A region entered with [`crate::region`], see [`Stats::into_tree_by_region`]
 ///
 /// The category is determined by the path of the file.
 pub enum Category {
@@ -294,6 +772,7 @@ pub enum Category {
     Deps,
     Application,
     Unknown,
+    Region,
 }
 
 fn guess_category(cwd: &str, filename: &str) -> Category {
@@ -320,10 +799,23 @@ mod test {
     fn test_tree_value_1() {
         let stats = Stats {
             deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
             allocations: VecDeque::from([Allocation {
                 allocation_size: 1024,
                 deallocation_size: 0,
                 address: 0,
+                previous_address: None,
+                weight: 1.0,
+                sequence: 0,
+                timestamp: Duration::ZERO,
+                thread_id: 0,
+                thread_name: None,
+                region: Vec::new(),
                 stack: VecDeque::from([
                     FrameInfo {
                         filename: Some("foo.rs".into()),
@@ -331,6 +823,7 @@ mod test {
                         lineno: Some(1),
                         fn_address: Some(std::ptr::null_mut()),
                         fn_name: Some("foo".into()),
+                        inlined: false,
                     },
                     FrameInfo {
                         filename: Some("foo2.rs".into()),
@@ -338,6 +831,7 @@ mod test {
                         lineno: Some(1),
                         fn_address: Some(std::ptr::null_mut()),
                         fn_name: Some("foo2".into()),
+                        inlined: false,
                     },
                     FrameInfo {
                         filename: Some("foo3.rs".into()),
@@ -345,8 +839,10 @@ mod test {
                         lineno: Some(1),
                         fn_address: Some(std::ptr::null_mut()),
                         fn_name: Some("foo3".into()),
+                        inlined: false,
                     },
                 ]),
+                truncated: false,
             }]),
         };
         let tree = stats.into_tree().unwrap();
@@ -361,12 +857,20 @@ mod test {
                     fn_address: std::ptr::null_mut(),
                     fn_name: "<root>".to_string(),
                     file_content: None,
+                    inlined: false,
                 },
                 allocation: 1024,
                 allocation_count: 1,
                 deallocation: 0,
                 deallocation_count: 0,
+                reallocation: 0,
+                reallocation_count: 0,
                 category: Category::Unknown,
+                capture: Some(CaptureInfo {
+                    dropped_events: 0,
+                    sampling_rate: None,
+                    peak: None,
+                }),
                 children: vec![Tree {
                     key: Key {
                         filename: "foo.rs".to_string(),
@@ -375,12 +879,16 @@ mod test {
                         fn_address: std::ptr::null_mut(),
                         fn_name: "foo".to_string(),
                         file_content: None,
+                        inlined: false,
                     },
                     allocation: 1024,
                     allocation_count: 1,
                     deallocation: 0,
                     deallocation_count: 0,
+                    reallocation: 0,
+                    reallocation_count: 0,
                     category: Category::Unknown,
+                    capture: None,
                     children: vec![Tree {
                         key: Key {
                             filename: "foo2.rs".to_string(),
@@ -389,12 +897,16 @@ mod test {
                             fn_address: std::ptr::null_mut(),
                             fn_name: "foo2".to_string(),
                             file_content: None,
+                            inlined: false,
                         },
                         allocation: 1024,
                         allocation_count: 1,
                         deallocation: 0,
                         deallocation_count: 0,
+                        reallocation: 0,
+                        reallocation_count: 0,
                         category: Category::Unknown,
+                        capture: None,
                         children: vec![Tree {
                             key: Key {
                                 filename: "foo3.rs".to_string(),
@@ -403,12 +915,16 @@ mod test {
                                 fn_address: std::ptr::null_mut(),
                                 fn_name: "foo3".to_string(),
                                 file_content: None,
+                                inlined: false,
                             },
                             allocation: 1024,
                             allocation_count: 1,
                             deallocation: 0,
                             deallocation_count: 0,
+                            reallocation: 0,
+                            reallocation_count: 0,
                             category: Category::Unknown,
+                            capture: None,
                             children: vec![],
                         }],
                     }],
@@ -421,11 +937,24 @@ mod test {
     fn test_tree_value_2() {
         let stats = Stats {
             deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
             allocations: VecDeque::from([
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -433,6 +962,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -440,6 +970,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo3.rs".into()),
@@ -447,13 +978,22 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo3".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -461,6 +1001,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -468,6 +1009,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo3.rs".into()),
@@ -475,8 +1017,10 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo3".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
             ]),
         };
@@ -492,12 +1036,20 @@ mod test {
                     fn_address: std::ptr::null_mut(),
                     fn_name: "<root>".to_string(),
                     file_content: None,
+                    inlined: false,
                 },
                 allocation: 1024 * 2,
                 allocation_count: 2,
                 deallocation: 0,
                 deallocation_count: 0,
+                reallocation: 0,
+                reallocation_count: 0,
                 category: Category::Unknown,
+                capture: Some(CaptureInfo {
+                    dropped_events: 0,
+                    sampling_rate: None,
+                    peak: None,
+                }),
                 children: vec![Tree {
                     key: Key {
                         filename: "foo.rs".to_string(),
@@ -506,12 +1058,16 @@ mod test {
                         fn_address: std::ptr::null_mut(),
                         fn_name: "foo".to_string(),
                         file_content: None,
+                        inlined: false,
                     },
                     allocation: 1024 * 2,
                     allocation_count: 2,
                     deallocation: 0,
                     deallocation_count: 0,
+                    reallocation: 0,
+                    reallocation_count: 0,
                     category: Category::Unknown,
+                    capture: None,
                     children: vec![Tree {
                         key: Key {
                             filename: "foo2.rs".to_string(),
@@ -520,12 +1076,16 @@ mod test {
                             fn_address: std::ptr::null_mut(),
                             fn_name: "foo2".to_string(),
                             file_content: None,
+                            inlined: false,
                         },
                         allocation: 1024 * 2,
                         allocation_count: 2,
                         deallocation: 0,
                         deallocation_count: 0,
+                        reallocation: 0,
+                        reallocation_count: 0,
                         category: Category::Unknown,
+                        capture: None,
                         children: vec![Tree {
                             key: Key {
                                 filename: "foo3.rs".to_string(),
@@ -534,12 +1094,16 @@ mod test {
                                 fn_address: std::ptr::null_mut(),
                                 fn_name: "foo3".to_string(),
                                 file_content: None,
+                                inlined: false,
                             },
                             allocation: 1024 * 2,
                             allocation_count: 2,
                             deallocation: 0,
                             deallocation_count: 0,
+                            reallocation: 0,
+                            reallocation_count: 0,
                             category: Category::Unknown,
+                            capture: None,
                             children: vec![],
                         }],
                     }],
@@ -552,11 +1116,24 @@ mod test {
     fn test_tree_value_3() {
         let stats = Stats {
             deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
             allocations: VecDeque::from([
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -564,6 +1141,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -571,6 +1149,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo3.rs".into()),
@@ -578,13 +1157,22 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo3".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -592,6 +1180,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -599,6 +1188,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo3.rs".into()),
@@ -606,6 +1196,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo3".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo4.rs".into()),
@@ -613,8 +1204,10 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo4".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
             ]),
         };
@@ -630,12 +1223,20 @@ mod test {
                     fn_address: std::ptr::null_mut(),
                     fn_name: "<root>".to_string(),
                     file_content: None,
+                    inlined: false,
                 },
                 allocation: 1024 * 2,
                 allocation_count: 2,
                 deallocation: 0,
                 deallocation_count: 0,
+                reallocation: 0,
+                reallocation_count: 0,
                 category: Category::Unknown,
+                capture: Some(CaptureInfo {
+                    dropped_events: 0,
+                    sampling_rate: None,
+                    peak: None,
+                }),
                 children: vec![Tree {
                     key: Key {
                         filename: "foo.rs".to_string(),
@@ -644,12 +1245,16 @@ mod test {
                         fn_address: std::ptr::null_mut(),
                         fn_name: "foo".to_string(),
                         file_content: None,
+                        inlined: false,
                     },
                     allocation: 1024 * 2,
                     allocation_count: 2,
                     deallocation: 0,
                     deallocation_count: 0,
+                    reallocation: 0,
+                    reallocation_count: 0,
                     category: Category::Unknown,
+                    capture: None,
                     children: vec![Tree {
                         key: Key {
                             filename: "foo2.rs".to_string(),
@@ -658,12 +1263,16 @@ mod test {
                             fn_address: std::ptr::null_mut(),
                             fn_name: "foo2".to_string(),
                             file_content: None,
+                            inlined: false,
                         },
                         allocation: 1024 * 2,
                         allocation_count: 2,
                         deallocation: 0,
                         deallocation_count: 0,
+                        reallocation: 0,
+                        reallocation_count: 0,
                         category: Category::Unknown,
+                        capture: None,
                         children: vec![Tree {
                             key: Key {
                                 filename: "foo3.rs".to_string(),
@@ -672,12 +1281,16 @@ mod test {
                                 fn_address: std::ptr::null_mut(),
                                 fn_name: "foo3".to_string(),
                                 file_content: None,
+                                inlined: false,
                             },
                             allocation: 1024 * 2,
                             allocation_count: 2,
                             deallocation: 0,
                             deallocation_count: 0,
+                            reallocation: 0,
+                            reallocation_count: 0,
                             category: Category::Unknown,
+                            capture: None,
                             children: vec![Tree {
                                 key: Key {
                                     filename: "foo4.rs".to_string(),
@@ -686,12 +1299,16 @@ mod test {
                                     fn_address: std::ptr::null_mut(),
                                     fn_name: "foo4".to_string(),
                                     file_content: None,
+                                    inlined: false,
                                 },
                                 allocation: 1024,
                                 allocation_count: 1,
                                 deallocation: 0,
                                 deallocation_count: 0,
+                                reallocation: 0,
+                                reallocation_count: 0,
                                 category: Category::Unknown,
+                                capture: None,
                                 children: vec![],
                             }],
                         }],
@@ -705,11 +1322,24 @@ mod test {
     fn test_tree_value_4() {
         let stats = Stats {
             deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
             allocations: VecDeque::from([
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -717,6 +1347,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -724,6 +1355,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo3.rs".into()),
@@ -731,13 +1363,22 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo3".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
                 Allocation {
                     allocation_size: 1024,
                     deallocation_size: 0,
                     address: 0,
+                    previous_address: None,
+                    weight: 1.0,
+                    sequence: 0,
+                    timestamp: Duration::ZERO,
+                    thread_id: 0,
+                    thread_name: None,
+                    region: Vec::new(),
                     stack: VecDeque::from([
                         FrameInfo {
                             filename: Some("foo.rs".into()),
@@ -745,6 +1386,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo2.rs".into()),
@@ -752,6 +1394,7 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo2".into()),
+                            inlined: false,
                         },
                         FrameInfo {
                             filename: Some("foo4.rs".into()),
@@ -759,8 +1402,10 @@ mod test {
                             lineno: Some(1),
                             fn_address: Some(std::ptr::null_mut()),
                             fn_name: Some("foo4".into()),
+                            inlined: false,
                         },
                     ]),
+                    truncated: false,
                 },
             ]),
         };
@@ -776,12 +1421,20 @@ mod test {
                     fn_address: std::ptr::null_mut(),
                     fn_name: "<root>".to_string(),
                     file_content: None,
+                    inlined: false,
                 },
                 allocation: 1024 * 2,
                 allocation_count: 2,
                 deallocation: 0,
                 deallocation_count: 0,
+                reallocation: 0,
+                reallocation_count: 0,
                 category: Category::Unknown,
+                capture: Some(CaptureInfo {
+                    dropped_events: 0,
+                    sampling_rate: None,
+                    peak: None,
+                }),
                 children: vec![Tree {
                     key: Key {
                         filename: "foo.rs".to_string(),
@@ -790,12 +1443,16 @@ mod test {
                         fn_address: std::ptr::null_mut(),
                         fn_name: "foo".to_string(),
                         file_content: None,
+                        inlined: false,
                     },
                     allocation: 1024 * 2,
                     allocation_count: 2,
                     deallocation: 0,
                     deallocation_count: 0,
+                    reallocation: 0,
+                    reallocation_count: 0,
                     category: Category::Unknown,
+                    capture: None,
                     children: vec![Tree {
                         key: Key {
                             filename: "foo2.rs".to_string(),
@@ -804,12 +1461,16 @@ mod test {
                             fn_address: std::ptr::null_mut(),
                             fn_name: "foo2".to_string(),
                             file_content: None,
+                            inlined: false,
                         },
                         allocation: 1024 * 2,
                         allocation_count: 2,
                         deallocation: 0,
                         deallocation_count: 0,
+                        reallocation: 0,
+                        reallocation_count: 0,
                         category: Category::Unknown,
+                        capture: None,
                         children: vec![
                             Tree {
                                 key: Key {
@@ -819,12 +1480,16 @@ mod test {
                                     fn_address: std::ptr::null_mut(),
                                     fn_name: "foo3".to_string(),
                                     file_content: None,
+                                    inlined: false,
                                 },
                                 allocation: 1024,
                                 allocation_count: 1,
                                 deallocation: 0,
                                 deallocation_count: 0,
+                                reallocation: 0,
+                                reallocation_count: 0,
                                 category: Category::Unknown,
+                                capture: None,
                                 children: vec![],
                             },
                             Tree {
@@ -835,12 +1500,16 @@ mod test {
                                     fn_address: std::ptr::null_mut(),
                                     fn_name: "foo4".to_string(),
                                     file_content: None,
+                                    inlined: false,
                                 },
                                 allocation: 1024,
                                 allocation_count: 1,
                                 deallocation: 0,
                                 deallocation_count: 0,
+                                reallocation: 0,
+                                reallocation_count: 0,
                                 category: Category::Unknown,
+                                capture: None,
                                 children: vec![],
                             },
                         ],
@@ -849,4 +1518,50 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn test_truncated_stacks() {
+        let allocation = |truncated| Allocation {
+            allocation_size: 1024,
+            deallocation_size: 0,
+            address: 0,
+            previous_address: None,
+            weight: 1.0,
+            sequence: 0,
+            timestamp: Duration::ZERO,
+            thread_id: 0,
+            thread_name: None,
+            region: Vec::new(),
+            stack: VecDeque::from([FrameInfo {
+                filename: Some("foo.rs".into()),
+                colno: Some(1),
+                lineno: Some(1),
+                fn_address: Some(std::ptr::null_mut()),
+                fn_name: Some("foo".into()),
+                inlined: false,
+            }]),
+            truncated,
+        };
+        let stats = Stats {
+            allocations: VecDeque::from([allocation(false), allocation(true)]),
+            deallocations: VecDeque::new(),
+            reallocations: VecDeque::new(),
+            failed_allocations: VecDeque::new(),
+            dropped_events: 0,
+            sampling_rate: None,
+            counters: Counters::default(),
+            peak: None,
+        };
+        let tree = stats.into_tree().unwrap();
+
+        let names: Vec<_> = tree
+            .children
+            .iter()
+            .map(|child| child.key.fn_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["foo", "<truncated>"]);
+        let truncated = &tree.children[1];
+        assert_eq!(truncated.allocation, 1024);
+        assert_eq!(truncated.children[0].key.fn_name, "foo");
+    }
 }
@@ -0,0 +1,618 @@
+//! Symbolization of the instruction pointers of a [`Capture`] from the
+//! executable files, possibly on another machine than the one which recorded it.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use object::{Object, ObjectSegment, ObjectSymbol};
+
+use crate::{
+    alloc::unrecorded,
+    capture::Capture,
+    stats::{FrameInfo, Stats},
+};
+
+/// Instruction pointers resolved by a worker between two progress reports
+const BATCH: usize = 256;
+
+/// Below this number of instruction pointers, `backtrace` resolves them faster,
+/// its caches being warm, than workers loading the executable files
+const PARALLEL_THRESHOLD: usize = 2048;
+
+/// Called with the number of instruction pointers resolved so far and their total
+type Progress = dyn Fn(usize, usize) + Send + Sync;
+
+/// An executable file mapped in the memory of the recorded process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// First address of the mapping
+    pub start: usize,
+    /// Address after the end of the mapping
+    pub end: usize,
+    /// Offset in the file of the first mapped byte
+    pub offset: usize,
+    /// Path of the file, in the recorded process
+    pub path: PathBuf,
+    /// GNU build ID of the file, to find its debug information elsewhere
+    pub build_id: Option<Vec<u8>>,
+}
+
+/// The executable mappings of the current process: parsed from `/proc/self/maps`
+/// on Linux, empty elsewhere.
+pub(crate) fn current_modules() -> Vec<Module> {
+    let Ok(maps) = std::fs::read_to_string("/proc/self/maps") else {
+        return Vec::new();
+    };
+
+    let mut build_ids: HashMap<PathBuf, Option<Vec<u8>>> = HashMap::new();
+    let mut modules = Vec::new();
+    for line in maps.lines() {
+        // start-end perms offset dev inode path
+        let mut fields = line.split_whitespace();
+        let (Some(range), Some(perms), Some(offset), Some(_), Some(_), Some(path)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            continue;
+        };
+        if !perms.contains('x') || !path.starts_with('/') {
+            continue;
+        }
+        let Some((start, end)) = range.split_once('-') else {
+            continue;
+        };
+        let (Ok(start), Ok(end), Ok(offset)) = (
+            usize::from_str_radix(start, 16),
+            usize::from_str_radix(end, 16),
+            usize::from_str_radix(offset, 16),
+        ) else {
+            continue;
+        };
+
+        let path = PathBuf::from(path);
+        let build_id = build_ids
+            .entry(path.clone())
+            .or_insert_with(|| read_build_id(&path))
+            .clone();
+        modules.push(Module {
+            start,
+            end,
+            offset,
+            path,
+            build_id,
+        });
+    }
+    modules
+}
+
+fn read_build_id(path: &Path) -> Option<Vec<u8>> {
+    let file = File::open(path).ok()?;
+    // Safety: the file is only read, and executables are not modified while they run
+    let data = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*data).ok()?;
+    object.build_id().ok()?.map(|id| id.to_vec())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/// The frames of an instruction pointer, moved from a worker to the thread
+/// building the stats
+struct Resolved(usize, Vec<FrameInfo>);
+
+// Safety: `FrameInfo::fn_address` is never dereferenced, it's only an address
+unsafe impl Send for Resolved {}
+
+/// Resolve each of the distinct instruction pointers `ips` into its frames,
+/// outermost first, spreading them across
+/// `threads` workers. Each worker symbolizes with its own resolver, made by
+/// `new_resolver`: the parsed debug information can't be shared between threads.
+/// Rallo's allocations aren't recorded meanwhile.
+fn resolve_all<F, R>(
+    ips: &[usize],
+    threads: usize,
+    progress: Option<&Progress>,
+    new_resolver: F,
+) -> HashMap<usize, Vec<FrameInfo>>
+where
+    F: Fn() -> R + Sync,
+    R: FnMut(usize) -> Vec<FrameInfo>,
+{
+    let total = ips.len();
+    let resolved = AtomicUsize::new(0);
+    let work = |ips: &[usize]| -> Vec<Resolved> {
+        unrecorded(|| {
+            let mut resolve = new_resolver();
+            let mut frames = Vec::with_capacity(ips.len());
+            for batch in ips.chunks(BATCH) {
+                frames.extend(batch.iter().map(|ip| Resolved(*ip, resolve(*ip))));
+                let done = resolved.fetch_add(batch.len(), Ordering::Relaxed) + batch.len();
+                if let Some(progress) = progress {
+                    progress(done, total);
+                }
+            }
+            frames
+        })
+    };
+
+    let threads = threads.clamp(1, total.div_ceil(BATCH).max(1));
+    let frames = if threads == 1 {
+        work(ips)
+    } else {
+        let work = &work;
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = ips
+                .chunks(total.div_ceil(threads))
+                .map(|ips| scope.spawn(move || work(ips)))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        })
+    };
+    frames
+        .into_iter()
+        .map(|Resolved(ip, frames)| (ip, frames))
+        .collect()
+}
+
+/// Resolve `ips` against the running program, see [`Capture::into_stats`]
+pub(crate) fn resolve_in_process(ips: &[usize]) -> HashMap<usize, Vec<FrameInfo>> {
+    let modules = if ips.len() < PARALLEL_THRESHOLD {
+        Vec::new()
+    } else {
+        current_modules()
+    };
+    if modules.is_empty() {
+        // `backtrace` resolves one address at a time: no use for more threads
+        return resolve_all(ips, 1, None, || resolve_frames);
+    }
+
+    let symbolizer = Symbolizer::new();
+    resolve_all(ips, default_threads(), None, || {
+        let mut loaded = HashMap::new();
+        let (symbolizer, modules) = (&symbolizer, &modules);
+        move |ip| {
+            let frames = symbolizer.resolve(modules, &mut loaded, ip);
+            if frames.iter().any(|frame| frame.fn_name.is_some()) {
+                frames
+            } else {
+                // Not in a file on disk, like the vDSO
+                resolve_frames(ip)
+            }
+        }
+    })
+}
+
+/// Resolve `ip`, a return address of the running program, with `backtrace`
+pub(crate) fn resolve_frames(ip: usize) -> Vec<FrameInfo> {
+    let mut frames = Vec::new();
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        frames.push(FrameInfo {
+            filename: symbol.filename().map(|f| f.to_owned()),
+            colno: symbol.colno(),
+            lineno: symbol.lineno(),
+            fn_address: symbol.addr(),
+            fn_name: symbol
+                .name()
+                .and_then(|name| name.as_str())
+                .map(|name| name.to_string()),
+            inlined: false,
+        });
+    });
+    outermost_first(frames)
+}
+
+/// Order the frames of an instruction pointer, given innermost first as by
+/// `backtrace::resolve`, from the function containing it to the innermost
+/// call inlined in it
+fn outermost_first(mut frames: Vec<FrameInfo>) -> Vec<FrameInfo> {
+    if frames.is_empty() {
+        return vec![unknown_frame()];
+    }
+    frames.reverse();
+    for (index, frame) in frames.iter_mut().enumerate() {
+        frame.inlined = index > 0;
+    }
+    frames
+}
+
+/// Resolves the instruction pointers of a [`Capture`] to functions, files and lines
+/// from the executable files of the recorded process.
+///
+/// The files are looked up at the path they had in the recorded process, and then
+/// in the search directories: by name, and by build ID as `.build-id/ab/cdef….debug`.
+/// A file is only used if its build ID matches the recorded one.
+///
+/// Each distinct instruction pointer is resolved once, by several threads.
+///
+/// ```rust,no_run
+/// use rallo::{Capture, Symbolizer};
+///
+/// let capture = Capture::read_file("capture.bin").unwrap();
+/// let stats = Symbolizer::new()
+///     .search_path("/usr/lib/debug")
+///     .progress(|resolved, total| eprint!("\rsymbolizing: {resolved}/{total}"))
+///     .symbolize(capture);
+/// ```
+#[derive(Default)]
+pub struct Symbolizer {
+    search_paths: Vec<PathBuf>,
+    threads: Option<usize>,
+    progress: Option<Box<Progress>>,
+}
+
+impl fmt::Debug for Symbolizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Symbolizer")
+            .field("search_paths", &self.search_paths)
+            .field("threads", &self.threads)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+impl Symbolizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also look for the executable files and their debug information in `dir`
+    pub fn search_path<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.search_paths.push(dir.into());
+        self
+    }
+
+    /// Number of threads resolving the instruction pointers.
+    /// By default, the available parallelism of the machine
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Call `progress` with the number of instruction pointers resolved so far,
+    /// and their total, every few hundreds of them. It's called from the worker threads
+    pub fn progress<F: Fn(usize, usize) + Send + Sync + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Symbolize the events of `capture` into [`Stats`].
+    ///
+    /// The frames of the modules whose files can't be found are left unknown.
+    pub fn symbolize(&self, capture: Capture) -> Stats {
+        let modules = capture.modules.clone();
+        let threads = self.threads.unwrap_or_else(default_threads);
+        capture.into_stats_with(|ips| {
+            resolve_all(ips, threads, self.progress.as_deref(), || {
+                let mut loaded = HashMap::new();
+                let modules = &modules;
+                move |ip| self.resolve(modules, &mut loaded, ip)
+            })
+        })
+    }
+
+    /// Resolve `ip`, loading the file of its module into `loaded` the first time
+    fn resolve(
+        &self,
+        modules: &[Module],
+        loaded: &mut HashMap<PathBuf, Option<LoadedModule>>,
+        ip: usize,
+    ) -> Vec<FrameInfo> {
+        let Some(module) = modules
+            .iter()
+            .find(|module| module.start <= ip && ip < module.end)
+        else {
+            return vec![unknown_frame()];
+        };
+        let loaded = loaded
+            .entry(module.path.clone())
+            .or_insert_with(|| self.load(module));
+        match loaded {
+            Some(loaded) => loaded.resolve(module, ip),
+            None => vec![unknown_frame()],
+        }
+    }
+
+    fn load(&self, module: &Module) -> Option<LoadedModule> {
+        let name = module.path.file_name()?;
+        let mut candidates = vec![module.path.clone()];
+        for dir in &self.search_paths {
+            candidates.push(dir.join(name));
+            if let Some(build_id) = module.build_id.as_deref().filter(|id| id.len() > 1) {
+                let build_id = hex(build_id);
+                let (head, tail) = build_id.split_at(2);
+                candidates.push(
+                    dir.join(".build-id")
+                        .join(head)
+                        .join(format!("{tail}.debug")),
+                );
+            }
+        }
+
+        candidates
+            .into_iter()
+            .filter(|path| path.exists())
+            .find(|path| module.build_id.is_none() || read_build_id(path) == module.build_id)
+            .and_then(|path| LoadedModule::load(&path))
+    }
+}
+
+/// The debug information of a module file
+struct LoadedModule {
+    loader: addr2line::Loader,
+    /// `(file offset, file size, address)` of the loaded segments
+    segments: Vec<(u64, u64, u64)>,
+    /// `(address, size, name)` of the function symbols, sorted by address
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl LoadedModule {
+    fn load(path: &Path) -> Option<LoadedModule> {
+        let file = File::open(path).ok()?;
+        // Safety: the file is only read
+        let data = unsafe { memmap2::Mmap::map(&file) }.ok()?;
+        let object = object::File::parse(&*data).ok()?;
+
+        let segments = object
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+        let mut symbols: Vec<(u64, u64, String)> = object
+            .symbols()
+            .chain(object.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == object::SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?.to_string();
+                Some((symbol.address(), symbol.size(), name))
+            })
+            .collect();
+        symbols.sort();
+
+        let loader = addr2line::Loader::new(path).ok()?;
+        Some(LoadedModule {
+            loader,
+            segments,
+            symbols,
+        })
+    }
+
+    /// Resolve `ip`, a return address in the mapping `module` of this file, into
+    /// the function containing it and the calls inlined there, outermost first
+    fn resolve(&self, module: &Module, ip: usize) -> Vec<FrameInfo> {
+        // The call instruction is just before the return address
+        let ip = ip.saturating_sub(1);
+        let offset = (ip - module.start + module.offset) as u64;
+        let Some((segment_offset, _, segment_address)) = self
+            .segments
+            .iter()
+            .find(|(start, size, _)| *start <= offset && offset < start + size)
+        else {
+            return vec![unknown_frame()];
+        };
+        // Address of `ip` in the file, as used by its symbols and debug information
+        let address = offset - segment_offset + segment_address;
+
+        let symbol = match self
+            .symbols
+            .partition_point(|(start, ..)| *start <= address)
+        {
+            0 => None,
+            i => Some(&self.symbols[i - 1]),
+        };
+        // Not in the padding after the function
+        let symbol = symbol.filter(|(start, size, _)| *size == 0 || address < start + size);
+
+        let symbol_name = symbol.map(|(.., name)| name.clone());
+
+        // As `backtrace::resolve`: innermost first, with the address of `ip` in
+        // the file, and the symbol table for the names missing in the debug information
+        let mut frames = Vec::new();
+        if let Ok(mut found_frames) = self.loader.find_frames(address) {
+            while let Ok(Some(found)) = found_frames.next() {
+                let name = found
+                    .function
+                    .as_ref()
+                    .and_then(|function| function.raw_name().ok())
+                    .map(|name| name.into_owned());
+                frames.push(FrameInfo {
+                    filename: found
+                        .location
+                        .as_ref()
+                        .and_then(|location| location.file)
+                        .map(PathBuf::from),
+                    colno: found.location.as_ref().and_then(|location| location.column),
+                    lineno: found.location.as_ref().and_then(|location| location.line),
+                    fn_address: Some(address as *mut c_void),
+                    fn_name: name.or_else(|| symbol_name.clone()),
+                    inlined: false,
+                });
+            }
+        }
+        if frames.is_empty() && symbol_name.is_some() {
+            frames.push(FrameInfo {
+                fn_name: symbol_name,
+                ..unknown_frame()
+            });
+        }
+        outermost_first(frames)
+    }
+}
+
+fn unknown_frame() -> FrameInfo {
+    FrameInfo {
+        filename: None,
+        colno: None,
+        lineno: None,
+        fn_address: None,
+        fn_name: None,
+        inlined: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    use crate::capture::{RawEvent, RawEventKind};
+
+    /// Frames of the stack `frames` as resolved by `symbolizer`, to check them
+    /// against the ones resolved in process
+    fn symbolize_frames(symbolizer: &Symbolizer, frames: &[usize]) -> VecDeque<FrameInfo> {
+        let capture = Capture {
+            modules: current_modules(),
+            events: vec![RawEvent {
+                kind: RawEventKind::Allocation,
+                size: 1,
+                previous_size: 0,
+                address: 0,
+                previous_address: 0,
+                weight: 1.0,
+                sequence: 0,
+                timestamp: 0,
+                thread_id: 0,
+                thread_name: None,
+                region: Vec::new(),
+                frames: frames.to_vec(),
+                truncated: false,
+            }],
+            ..Capture::default()
+        };
+        let mut stats = symbolizer.symbolize(capture);
+        stats.allocations.pop_front().unwrap().stack
+    }
+
+    #[inline(never)]
+    fn current_ips() -> Vec<usize> {
+        let mut ips = Vec::new();
+        backtrace::trace(|frame| {
+            ips.push(frame.ip() as usize);
+            ips.len() < 8
+        });
+        ips
+    }
+
+    #[test]
+    fn test_current_modules() {
+        if !Path::new("/proc/self/maps").exists() {
+            return;
+        }
+        let modules = current_modules();
+        let executable = std::env::current_exe().unwrap();
+        let module = modules
+            .iter()
+            .find(|module| module.path == executable)
+            .unwrap();
+        assert!(module.build_id.is_some());
+        let ip = test_current_modules as *const () as usize;
+        assert!(modules.iter().any(|m| m.start <= ip && ip < m.end));
+    }
+
+    #[test]
+    fn test_symbolize_like_backtrace() {
+        if !Path::new("/proc/self/maps").exists() {
+            return;
+        }
+        let ips = current_ips();
+        let offline = symbolize_frames(&Symbolizer::new(), &ips);
+        let in_process: VecDeque<_> = ips
+            .iter()
+            .rev()
+            .flat_map(|ip| resolve_frames(*ip))
+            .collect();
+
+        assert_eq!(offline.len(), in_process.len());
+        let current = offline
+            .iter()
+            .find(|frame| {
+                frame
+                    .fn_name
+                    .as_deref()
+                    .is_some_and(|name| name.contains("test_symbolize_like_backtrace"))
+            })
+            .unwrap();
+        let expected = in_process
+            .iter()
+            .find(|frame| frame.fn_name == current.fn_name)
+            .unwrap();
+        assert_eq!(current.filename, expected.filename);
+        assert_eq!(current.lineno, expected.lineno);
+        assert_eq!(current.fn_address, expected.fn_address);
+    }
+
+    #[test]
+    fn test_resolve_in_process() {
+        if !Path::new("/proc/self/maps").exists() {
+            return;
+        }
+        // Enough addresses for the workers, from this function on
+        let start = test_resolve_in_process as *const () as usize;
+        let ips: Vec<usize> = (start + 1..start + PARALLEL_THRESHOLD + 1).collect();
+        let frames = resolve_in_process(&ips);
+        for ip in ips {
+            let expected = resolve_frames(ip);
+            assert_eq!(frames[&ip].len(), expected.len());
+            for (frame, expected) in frames[&ip].iter().zip(expected) {
+                assert_eq!(frame.fn_name, expected.fn_name);
+                assert_eq!(frame.filename, expected.filename);
+                assert_eq!(frame.lineno, expected.lineno);
+                assert_eq!(frame.fn_address, expected.fn_address);
+                assert_eq!(frame.inlined, expected.inlined);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resolve_in_parallel() {
+        if !Path::new("/proc/self/maps").exists() {
+            return;
+        }
+        // Every return address of this function, to get more than one batch
+        let start = test_resolve_in_parallel as *const () as usize;
+        let ips: Vec<usize> = (start + 1..start + 2 * BATCH + 1).collect();
+        let progress = std::sync::Arc::new(AtomicUsize::new(0));
+        let symbolizer = Symbolizer::new().threads(4).progress({
+            let progress = progress.clone();
+            move |resolved, total| {
+                assert_eq!(total, 2 * BATCH);
+                progress.fetch_max(resolved, Ordering::Relaxed);
+            }
+        });
+
+        let parallel = symbolize_frames(&symbolizer, &ips);
+        let serial = symbolize_frames(&Symbolizer::new().threads(1), &ips);
+        assert_eq!(progress.load(Ordering::Relaxed), 2 * BATCH);
+        assert_eq!(parallel.len(), serial.len());
+        for (parallel, serial) in parallel.iter().zip(&serial) {
+            assert_eq!(parallel.fn_name, serial.fn_name);
+            assert_eq!(parallel.lineno, serial.lineno);
+        }
+        // Outermost first: the last one is the first address
+        assert!(
+            parallel[parallel.len() - 1]
+                .fn_name
+                .as_deref()
+                .is_some_and(|name| name.contains("test_resolve_in_parallel"))
+        );
+    }
+}
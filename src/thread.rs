@@ -0,0 +1,54 @@
+use std::cell::Cell;
+
+/// Maximum number of bytes of a thread name kept for each event
+pub(crate) const THREAD_NAME_LENGTH: usize = 32;
+
+thread_local! {
+    // `const` initialized without destructor: accessing it never allocates,
+    // so it is safe to use from inside the global allocator.
+    static THREAD_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Identifier of the current thread, as known by the OS when possible.
+pub(crate) fn current_thread_id() -> u64 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(os_thread_id());
+        }
+        id.get()
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> u64 {
+    // Safety: `gettid` has no preconditions
+    unsafe { libc::gettid() as u64 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn os_thread_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // No portable OS thread id: number the threads in order of appearance
+    static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Copy the name of the current thread into `buffer`, truncating it if needed.
+/// Returns the number of bytes written, 0 for unnamed threads.
+///
+/// `std::thread::current` may allocate the first time it is called on a thread
+/// not spawned by the standard library: the caller must not record that allocation.
+pub(crate) fn current_thread_name(buffer: &mut [u8; THREAD_NAME_LENGTH]) -> usize {
+    let thread = std::thread::current();
+    let Some(name) = thread.name() else {
+        return 0;
+    };
+
+    let mut len = name.len().min(THREAD_NAME_LENGTH);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    buffer[..len].copy_from_slice(&name.as_bytes()[..len]);
+    len
+}
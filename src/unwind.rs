@@ -0,0 +1,148 @@
+//! Capture of the return addresses of the current stack, for each recorded event.
+
+/// How the stacks of the events are captured, see [`crate::TrackOptions::unwinder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Unwinder {
+    /// Unwind with the DWARF information through `backtrace`: works with any
+    /// binary, but costs microseconds per event
+    #[default]
+    Backtrace,
+    /// Follow the chain of frame pointers: a few nanoseconds per frame, but the
+    /// functions on the stack must keep their frame pointer. Build with
+    /// `RUSTFLAGS="-C force-frame-pointers=yes"`: the stacks stop early at the
+    /// functions compiled without it.
+    ///
+    /// Only on Linux x86_64 and aarch64, elsewhere it falls back to
+    /// [`Unwinder::Backtrace`].
+    FramePointers,
+}
+
+impl Unwinder {
+    /// Call `f` with the instruction pointers of the current stack, innermost
+    /// first, until it returns `false`
+    #[inline(always)]
+    pub(crate) fn trace<F: FnMut(usize) -> bool>(self, mut f: F) {
+        match self {
+            #[cfg(all(
+                target_os = "linux",
+                any(target_arch = "x86_64", target_arch = "aarch64")
+            ))]
+            Unwinder::FramePointers => frame_pointers::trace(&mut f),
+            _ => backtrace::trace(|frame| f(frame.ip() as usize)),
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod frame_pointers {
+    use std::{cell::Cell, mem::MaybeUninit};
+
+    thread_local! {
+        // `const` initialized without destructor: accessing it never allocates,
+        // so it is safe to use from inside the global allocator.
+        static STACK_BOUNDS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    /// Walk the frame records `[caller's frame pointer, return address]` from
+    /// the one of this function. Only the addresses inside the stack of the
+    /// thread are read: a function without frame pointer ends the walk
+    /// instead of crashing it.
+    #[inline(never)]
+    pub(super) fn trace(f: &mut dyn FnMut(usize) -> bool) {
+        let (low, high) = stack_bounds();
+        let mut fp = frame_pointer();
+        while fp >= low
+            && fp.saturating_add(2 * size_of::<usize>()) <= high
+            && fp.is_multiple_of(align_of::<usize>())
+        {
+            // Safety: the record is inside the stack of the current thread
+            let [next, ip] = unsafe { (fp as *const [usize; 2]).read() };
+            if ip == 0 || !f(ip) {
+                break;
+            }
+            // The stack grows down: the callers' frames are above
+            if next <= fp {
+                break;
+            }
+            fp = next;
+        }
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> usize {
+        let fp: usize;
+        // Safety: only reads a register
+        unsafe {
+            #[cfg(target_arch = "x86_64")]
+            std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+            #[cfg(target_arch = "aarch64")]
+            std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        }
+        fp
+    }
+
+    /// `[low, high)` addresses of the stack of the current thread, `(0, 0)` if unknown
+    fn stack_bounds() -> (usize, usize) {
+        STACK_BOUNDS.with(|bounds| {
+            if bounds.get().1 == 0 {
+                bounds.set(query_stack_bounds());
+            }
+            bounds.get()
+        })
+    }
+
+    fn query_stack_bounds() -> (usize, usize) {
+        let mut attr = MaybeUninit::<libc::pthread_attr_t>::uninit();
+        let mut address = std::ptr::null_mut();
+        let mut size = 0;
+        // Safety: `attr` is initialized by `pthread_getattr_np` before use, and destroyed after
+        unsafe {
+            if libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr()) != 0 {
+                return (0, 0);
+            }
+            let result = libc::pthread_attr_getstack(attr.as_ptr(), &mut address, &mut size);
+            libc::pthread_attr_destroy(attr.as_mut_ptr());
+            if result != 0 {
+                return (0, 0);
+            }
+        }
+        (address as usize, address as usize + size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// The stacks found by each unwinder, from the same call site
+    #[inline(never)]
+    fn trace(unwinders: [Unwinder; 2]) -> [Vec<usize>; 2] {
+        unwinders.map(|unwinder| {
+            let mut ips = Vec::new();
+            unwinder.trace(|ip| {
+                ips.push(ip);
+                true
+            });
+            ips
+        })
+    }
+
+    #[test]
+    fn test_frame_pointers_match_backtrace() {
+        let [backtrace, frame_pointers] = trace([Unwinder::Backtrace, Unwinder::FramePointers]);
+        let backtrace: HashSet<_> = backtrace.into_iter().collect();
+        // The functions built without frame pointer are skipped, like the ones
+        // of this test unless built with `-C force-frame-pointers=yes`, but the
+        // return addresses found are the ones `backtrace` finds, except the
+        // innermost one, inside the `match` of `Unwinder::trace`
+        assert!(!frame_pointers.is_empty());
+        for &ip in &frame_pointers[1..] {
+            assert!(backtrace.contains(&ip), "{ip:#x} not found by backtrace");
+        }
+    }
+}
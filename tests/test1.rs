@@ -1,14 +1,12 @@
 #![allow(clippy::borrowed_box)]
-
+#![allow(clippy::op_ref, clippy::needless_borrows_for_generic_args)]
 use std::{collections::VecDeque, fmt::Debug, path::PathBuf};
 
 use rallo::{FrameInfo, RalloAllocator, Tree};
 use serde::Serialize;
 
-const MAX_FRAME_LENGTH: usize = 128;
-const MAX_LOG_COUNT: usize = 1_024 * 10;
 #[global_allocator]
-static ALLOCATOR: RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT> = RalloAllocator::new();
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
 
 #[inline(never)]
 fn run() {
@@ -17,10 +15,7 @@ fn run() {
 
 #[test]
 fn test1() {
-    unsafe { ALLOCATOR.start_track() };
-    run();
-    ALLOCATOR.stop_track();
-    let stats = unsafe { ALLOCATOR.calculate_stats() };
+    let stats = ALLOCATOR.track(run).unwrap();
 
     let current_file: &PathBuf = &std::fs::canonicalize(file!()).unwrap();
 
@@ -28,13 +23,13 @@ fn test1() {
     assert_eq!(stats.allocations[0].allocation_size, 1024);
     let allocation =
         extrapolate_frame(&stats.allocations[0].stack, "::run::", current_file).unwrap();
-    assert_eq!(allocation.lineno, Some(15));
+    assert_eq!(allocation.lineno, Some(13));
 
     assert_eq!(stats.deallocations.len(), 1);
     assert_eq!(stats.deallocations[0].deallocation_size, 1024);
     let deallocation =
         extrapolate_frame(&stats.deallocations[0].stack, "::run::", current_file).unwrap();
-    assert_eq!(deallocation.lineno, Some(15));
+    assert_eq!(deallocation.lineno, Some(13));
 
     let tree = stats.into_tree().unwrap();
 
@@ -0,0 +1,49 @@
+use rallo::{Capture, RalloAllocator, Stats, Symbolizer};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn allocate(size: usize) -> Vec<u8> {
+    vec![0_u8; size]
+}
+
+/// Line of the frame of `allocate` in the stack of the allocation of `size` bytes
+fn allocate_line(stats: &Stats, size: usize) -> Option<u32> {
+    let allocation = stats
+        .allocations
+        .iter()
+        .find(|allocation| allocation.allocation_size == size)?;
+    allocation
+        .stack
+        .iter()
+        .find(|frame| {
+            frame
+                .fn_name
+                .as_deref()
+                .is_some_and(|name| name.contains("test10") && name.contains("allocate"))
+        })?
+        .lineno
+}
+
+#[test]
+fn test_offline_symbolization() {
+    let guard = ALLOCATOR.start().unwrap();
+    let v = allocate(4567);
+    let capture = guard.finish_capture().unwrap();
+    drop(v);
+
+    let mut bytes = Vec::new();
+    capture.write(&mut bytes).unwrap();
+    let in_process = Capture::read(bytes.as_slice()).unwrap().into_stats();
+    assert_eq!(allocate_line(&in_process, 4567), Some(8));
+
+    let capture = Capture::read(bytes.as_slice()).unwrap();
+    if capture.modules.is_empty() {
+        // No memory maps on this platform
+        return;
+    }
+    let offline = Symbolizer::new().symbolize(capture);
+    assert_eq!(allocate_line(&offline, 4567), Some(8));
+    assert_eq!(offline.allocations.len(), in_process.allocations.len());
+}
@@ -0,0 +1,74 @@
+use std::{path::Path, process::Command};
+
+use rallo::{Capture, RalloAllocator};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn allocate(size: usize) -> Vec<u8> {
+    vec![0_u8; size]
+}
+
+/// Capture a session allocating `count` blocks from the same call site
+fn session(count: usize) -> Capture {
+    let guard = ALLOCATOR.start().unwrap();
+    let blocks: Vec<_> = (0..count).map(|_| allocate(4567)).collect();
+    let capture = guard.finish_capture().unwrap();
+    drop(blocks);
+    capture
+}
+
+/// Run the `rallo` tool, returning its standard output
+fn rallo(args: &[&Path]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rallo"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_command_line() {
+    let dir = std::env::temp_dir().join(format!("rallo-test11-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name);
+
+    let before = session(1);
+    let after = session(2);
+
+    before.write_file(path("before.bin")).unwrap();
+    after.into_stats().write_json(path("after.json")).unwrap();
+
+    let summary = rallo(&["summary".as_ref(), &path("after.json")]);
+    assert!(summary.contains("2 allocation(s), 9134 bytes"), "{summary}");
+    assert!(summary.contains("test11::allocate"), "{summary}");
+
+    rallo(&[
+        "html".as_ref(),
+        &path("after.json"),
+        "-o".as_ref(),
+        &path("after.html"),
+    ]);
+    assert!(path("after.html").exists());
+    rallo(&[
+        "firefox".as_ref(),
+        &path("after.json"),
+        "-o".as_ref(),
+        &path("after.firefox.json"),
+    ]);
+    assert!(path("after.firefox.json").exists());
+
+    if !before.modules.is_empty() {
+        // The capture is symbolized from the executable
+        let diff = rallo(&["diff".as_ref(), &path("before.bin"), &path("after.json")]);
+        assert!(diff.contains("+4567 bytes (4567 -> 9134)"), "{diff}");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
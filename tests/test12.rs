@@ -0,0 +1,58 @@
+use rallo::{Capture, RalloAllocator, Stats, Symbolizer};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(always)]
+fn inlined() -> Vec<u8> {
+    vec![0_u8; 7777]
+}
+
+#[inline(never)]
+fn caller() -> Vec<u8> {
+    inlined()
+}
+
+/// `(name, inlined)` of the frames of `test12` in the stack of the allocation
+fn frames(stats: &Stats) -> Vec<(String, bool)> {
+    let allocation = stats
+        .allocations
+        .iter()
+        .find(|allocation| allocation.allocation_size == 7777)
+        .unwrap();
+    allocation
+        .stack
+        .iter()
+        .filter_map(|frame| {
+            let name = format!("{:#}", rustc_demangle::demangle(frame.fn_name.as_deref()?));
+            name.starts_with("test12::")
+                .then_some((name, frame.inlined))
+        })
+        .collect()
+}
+
+#[test]
+fn test_inlined_frames() {
+    let guard = ALLOCATOR.start().unwrap();
+    let v = caller();
+    let capture = guard.finish_capture().unwrap();
+    drop(v);
+
+    let mut bytes = Vec::new();
+    capture.write(&mut bytes).unwrap();
+    let expected = vec![
+        ("test12::caller".to_string(), false),
+        ("test12::inlined".to_string(), true),
+    ];
+
+    let in_process = Capture::read(bytes.as_slice()).unwrap().into_stats();
+    let in_process = frames(&in_process);
+    assert!(in_process.ends_with(&expected), "{in_process:?}");
+
+    let capture = Capture::read(bytes.as_slice()).unwrap();
+    if capture.modules.is_empty() {
+        return;
+    }
+    let offline = frames(&Symbolizer::new().symbolize(capture));
+    assert_eq!(offline, in_process);
+}
@@ -0,0 +1,27 @@
+use rallo::{BudgetPolicy, RalloAllocator, TrackOptions};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn allocate(size: usize) -> Vec<u8> {
+    vec![0_u8; size]
+}
+
+#[test]
+fn test_snapshot_is_not_counted() {
+    // Symbolizing the snapshot allocates far more than the budget
+    let options = TrackOptions::new().memory_budget(1 << 20, BudgetPolicy::ReturnNull);
+    let guard = ALLOCATOR.start_with(options).unwrap();
+    let a = allocate(1000);
+    let snapshot = ALLOCATOR.snapshot().unwrap();
+    let b = allocate(2000);
+    let stats = guard.finish().unwrap();
+
+    assert_eq!(snapshot.counters.total_allocations, 1);
+    assert_eq!(snapshot.counters.live_bytes, 1000);
+    assert_eq!(stats.counters.total_allocations, 2);
+    assert_eq!(stats.counters.total_deallocations, 0);
+    assert_eq!(stats.counters.live_bytes, 3000);
+    drop((a, b, snapshot));
+}
@@ -1,14 +1,12 @@
 #![allow(clippy::borrowed_box)]
-
+#![allow(clippy::op_ref, clippy::needless_borrows_for_generic_args)]
 use std::{collections::VecDeque, fmt::Debug, path::PathBuf};
 
 use rallo::{FrameInfo, RalloAllocator, Tree};
 use serde::Serialize;
 
-const MAX_FRAME_LENGTH: usize = 128;
-const MAX_LOG_COUNT: usize = 1_024 * 10;
 #[global_allocator]
-static ALLOCATOR: RalloAllocator<MAX_FRAME_LENGTH, MAX_LOG_COUNT> = RalloAllocator::new();
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
 
 #[inline(never)]
 fn run_child() {
@@ -23,10 +21,7 @@ fn run_parent() {
 
 #[test]
 fn test2() {
-    unsafe { ALLOCATOR.start_track() };
-    run_parent();
-    ALLOCATOR.stop_track();
-    let stats = unsafe { ALLOCATOR.calculate_stats() };
+    let stats = ALLOCATOR.track(run_parent).unwrap();
 
     let current_file: &PathBuf = &std::fs::canonicalize(file!()).unwrap();
 
@@ -35,24 +30,24 @@ fn test2() {
     assert_eq!(stats.allocations[1].allocation_size, 1024);
     let allocation =
         extrapolate_frame(&stats.allocations[0].stack, "::run_child::", current_file).unwrap();
-    assert_eq!(allocation.lineno, Some(15));
+    assert_eq!(allocation.lineno, Some(13));
     let allocation =
         extrapolate_frame(&stats.allocations[1].stack, "::run_parent::", current_file).unwrap();
-    assert_eq!(allocation.lineno, Some(20));
+    assert_eq!(allocation.lineno, Some(18));
 
     assert_eq!(stats.deallocations.len(), 2);
     assert_eq!(stats.deallocations[0].deallocation_size, 512);
     assert_eq!(stats.deallocations[1].deallocation_size, 1024);
     let deallocation =
         extrapolate_frame(&stats.deallocations[0].stack, "::run_child::", current_file).unwrap();
-    assert_eq!(deallocation.lineno, Some(15));
+    assert_eq!(deallocation.lineno, Some(13));
     let deallocation = extrapolate_frame(
         &stats.deallocations[0].stack,
         "::run_parent::",
         current_file,
     )
     .unwrap();
-    assert_eq!(deallocation.lineno, Some(21));
+    assert_eq!(deallocation.lineno, Some(19));
 
     let tree = stats.into_tree().unwrap();
 
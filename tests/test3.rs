@@ -0,0 +1,79 @@
+use std::{collections::VecDeque, path::PathBuf};
+
+use rallo::{EventKind, FrameInfo, RalloAllocator};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn run() {
+    let mut v: Vec<u8> = Vec::with_capacity(16);
+    v.extend_from_slice(&[0_u8; 32]);
+}
+
+#[test]
+fn test_realloc() {
+    let stats = ALLOCATOR.track(run).unwrap();
+
+    let current_file: &PathBuf = &std::fs::canonicalize(file!()).unwrap();
+
+    assert_eq!(stats.allocations.len(), 1);
+    assert_eq!(stats.allocations[0].allocation_size, 16);
+
+    assert_eq!(stats.reallocations.len(), 1);
+    let reallocation = &stats.reallocations[0];
+    assert_eq!(reallocation.deallocation_size, 16);
+    assert_eq!(reallocation.allocation_size, 32);
+    assert_eq!(
+        reallocation.previous_address,
+        Some(stats.allocations[0].address)
+    );
+    let frame = extrapolate_frame(&reallocation.stack, "::run::", current_file).unwrap();
+    assert_eq!(frame.lineno, Some(11));
+
+    assert_eq!(stats.deallocations.len(), 1);
+    assert_eq!(stats.deallocations[0].deallocation_size, 32);
+    assert_eq!(stats.deallocations[0].address, reallocation.address);
+
+    let timeline = stats.timeline();
+    let kinds: Vec<_> = timeline.iter().map(|(kind, _)| *kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            EventKind::Allocation,
+            EventKind::Reallocation,
+            EventKind::Deallocation
+        ]
+    );
+    let sequences: Vec<_> = timeline.iter().map(|(_, event)| event.sequence).collect();
+    assert_eq!(sequences, vec![0, 1, 2]);
+    assert!(
+        timeline
+            .windows(2)
+            .all(|pair| pair[0].1.timestamp <= pair[1].1.timestamp)
+    );
+
+    let tree = stats.into_tree().unwrap();
+
+    assert_eq!(tree.allocation, 16);
+    assert_eq!(tree.allocation_count, 1);
+    assert_eq!(tree.deallocation, 32);
+    assert_eq!(tree.deallocation_count, 1);
+    assert_eq!(tree.reallocation, 32);
+    assert_eq!(tree.reallocation_count, 1);
+}
+
+fn extrapolate_frame<'f>(
+    frames: &'f VecDeque<FrameInfo>,
+    wanted_fn_name: &str,
+    filename: &PathBuf,
+) -> Option<&'f FrameInfo> {
+    frames.iter().find(|f| {
+        if let Some(fn_name) = &f.fn_name {
+            let fn_name = rustc_demangle::demangle(fn_name).to_string();
+            f.filename.as_ref() == Some(filename) && fn_name.contains(wanted_fn_name)
+        } else {
+            false
+        }
+    })
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rallo::{FaultInjection, RalloAllocator, TrackOptions};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn run() {
+    let _ = vec![0_u8; 1024];
+}
+
+/// Set once the `failing` thread made its allocation fail
+static FAILED: AtomicBool = AtomicBool::new(false);
+/// Set once the `failing` thread can exit
+static DONE: AtomicBool = AtomicBool::new(false);
+
+#[test]
+fn test_threads() {
+    let worker = std::thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(|| {
+            std::thread::park();
+            run();
+        })
+        .unwrap();
+    // Its only events are failed allocations: it exits after the session
+    let failing = std::thread::Builder::new()
+        .name("failing".to_string())
+        .spawn(|| {
+            std::thread::park();
+            assert!(Vec::<u8>::new().try_reserve_exact(4099).is_err());
+            FAILED.store(true, Ordering::SeqCst);
+            while !DONE.load(Ordering::SeqCst) {
+                std::thread::park();
+            }
+        })
+        .unwrap();
+
+    let options = TrackOptions::new().fail_allocations(FaultInjection::Size(|size| size == 4099));
+    let stats = ALLOCATOR
+        .track_with(options, || {
+            run();
+            worker.thread().unpark();
+            worker.join().unwrap();
+            failing.thread().unpark();
+            while !FAILED.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+        })
+        .unwrap();
+    DONE.store(true, Ordering::SeqCst);
+    failing.thread().unpark();
+    failing.join().unwrap();
+
+    let threads = stats.threads();
+    let worker_id = threads
+        .iter()
+        .find(|(_, name)| name.as_deref() == Some("worker"))
+        .map(|(id, _)| *id)
+        .unwrap();
+    let current_id = stats
+        .allocations
+        .iter()
+        .map(|allocation| allocation.thread_id)
+        .find(|id| *id != worker_id)
+        .unwrap();
+    assert_ne!(worker_id, current_id);
+
+    let failing_id = threads
+        .iter()
+        .find(|(_, name)| name.as_deref() == Some("failing"))
+        .map(|(id, _)| *id)
+        .unwrap();
+    let failing_stats = stats.clone().filter_by_thread(failing_id);
+    assert!(failing_stats.allocations.is_empty());
+    assert_eq!(failing_stats.failed_allocations.len(), 1);
+    assert_eq!(failing_stats.failed_allocations[0].allocation_size, 4099);
+
+    let worker_stats = stats.filter_by_thread(worker_id);
+    assert!(worker_stats.failed_allocations.is_empty());
+    assert!(!worker_stats.allocations.is_empty());
+    assert!(
+        worker_stats
+            .allocations
+            .iter()
+            .all(|allocation| allocation.thread_name.as_deref() == Some("worker"))
+    );
+    assert!(
+        worker_stats
+            .allocations
+            .iter()
+            .any(|allocation| allocation.allocation_size == 1024)
+    );
+}
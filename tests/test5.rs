@@ -0,0 +1,29 @@
+use rallo::{BudgetPolicy, RalloAllocator, TrackOptions};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn parse(input_len: usize) -> Result<Vec<u8>, std::collections::TryReserveError> {
+    let mut output = Vec::new();
+    output.try_reserve_exact(input_len)?;
+    output.resize(input_len, 0);
+    Ok(output)
+}
+
+#[test]
+fn test_memory_budget() {
+    let options = TrackOptions::new().memory_budget(64 * 1024, BudgetPolicy::ReturnNull);
+
+    let mut results = Vec::new();
+    let stats = ALLOCATOR
+        .track_with(options, || {
+            results.push(parse(1024).map(|output| output.len()));
+            results.push(parse(1024 * 1024).map(|output| output.len()));
+        })
+        .unwrap();
+
+    assert_eq!(results[0], Ok(1024));
+    assert!(results[1].is_err());
+    assert!(stats.counters.peak_live_bytes <= 64 * 1024);
+}
@@ -0,0 +1,74 @@
+use rallo::{RalloAllocator, assert_allocs, assert_no_alloc};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn allocate(size: usize) -> Vec<u8> {
+    vec![0_u8; size]
+}
+
+#[test]
+fn test_assertions() {
+    let numbers = [1, 2, 3];
+    assert_no_alloc!(ALLOCATOR, || {
+        let _: u32 = numbers.iter().sum();
+    });
+    assert_allocs!(ALLOCATOR, max_count = 2, max_bytes = 1024, || {
+        allocate(512);
+        allocate(512);
+    });
+
+    let panic = std::panic::catch_unwind(|| {
+        assert_allocs!(ALLOCATOR, max_count = 1, || {
+            allocate(128);
+            allocate(256);
+        });
+    })
+    .unwrap_err();
+    let report = panic.downcast_ref::<String>().unwrap();
+
+    assert!(report.contains("allocations: expected at most 1, got 2"));
+    assert!(!report.contains("bytes:"));
+    // The largest call site first, pointing to the code which allocated
+    let current_file = std::fs::canonicalize(file!()).unwrap();
+    let site = format!("test6::allocate ({}:8)", current_file.display());
+    assert!(report.contains(&site), "{report}");
+    assert!(report.find("256 bytes").unwrap() < report.find("128 bytes").unwrap());
+}
+
+#[test]
+fn test_parallel_assertions() {
+    let barrier = std::sync::Barrier::new(2);
+    std::thread::scope(|scope| {
+        for size in [100, 200] {
+            let barrier = &barrier;
+            scope.spawn(move || {
+                barrier.wait();
+                for _ in 0..50 {
+                    assert_allocs!(ALLOCATOR, max_count = 1, max_bytes = size, || {
+                        allocate(size);
+                    });
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_assertion_next_to_busy_thread() {
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        // Fills the buffers many times over while the assertion runs
+        scope.spawn(|| {
+            while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                allocate(8);
+            }
+        });
+        assert_allocs!(ALLOCATOR, max_count = 1, || {
+            allocate(64);
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        });
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+}
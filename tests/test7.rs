@@ -0,0 +1,84 @@
+use rallo::{
+    Category, FaultInjection, FirefoxProfile, RalloAllocator, TrackOptions, enter_region, region,
+};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn run() {
+    region("index", || {
+        let _ = vec![0_u8; 1024];
+        let _merge = enter_region("merge");
+        let _ = vec![0_u8; 512];
+    });
+    let _ = vec![0_u8; 256];
+}
+
+#[test]
+fn test_regions() {
+    let stats = ALLOCATOR.track(run).unwrap();
+
+    let regions: Vec<_> = stats.regions().into_iter().collect();
+    assert_eq!(regions, vec![vec![], vec!["index"], vec!["index", "merge"]]);
+
+    let sizes = |stats: &rallo::Stats| {
+        stats
+            .allocations
+            .iter()
+            .map(|allocation| allocation.allocation_size)
+            .collect::<Vec<_>>()
+    };
+    let path = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+    };
+    let mut groups = ALLOCATOR.track(run).unwrap().group_by_region();
+    assert_eq!(sizes(&groups[&path(&[])]), vec![256]);
+    assert_eq!(sizes(&groups[&path(&["index"])]), vec![1024]);
+    assert_eq!(sizes(&groups[&path(&["index", "merge"])]), vec![512]);
+
+    let index = groups.remove(&path(&["index"])).unwrap();
+    assert_eq!(index.deallocations.len(), 1);
+
+    let filtered = ALLOCATOR.track(run).unwrap().filter_by_region("index");
+    assert_eq!(sizes(&filtered), vec![512, 1024]);
+
+    let tree = stats.into_tree_by_region().unwrap();
+    let index = tree
+        .children
+        .iter()
+        .find(|child| child.key.fn_name == "index")
+        .unwrap();
+    assert!(matches!(index.category, Category::Region));
+    assert_eq!(index.allocation, 1024 + 512);
+    let merge = index
+        .children
+        .iter()
+        .find(|child| child.key.fn_name == "merge")
+        .unwrap();
+    assert_eq!(merge.allocation, 512);
+
+    let profile = FirefoxProfile::from_stats_by_region(ALLOCATOR.track(run).unwrap()).unwrap();
+    let json = profile.to_json_string().unwrap();
+    assert!(json.contains("\"merge\""));
+    assert!(json.contains("\"Region\""));
+
+    // The failed allocations are counted like the other events
+    let options = TrackOptions::new().fail_allocations(FaultInjection::Size(|size| size == 4099));
+    let stats = ALLOCATOR
+        .track_with(options, || {
+            region("reserve", || {
+                let _ = Vec::<u8>::new().try_reserve_exact(4099);
+            });
+        })
+        .unwrap();
+    assert_eq!(stats.failed_allocations.len(), 1);
+    assert!(stats.regions().contains(&vec!["reserve".to_string()]));
+    let groups = stats.clone().group_by_region();
+    assert_eq!(groups.len(), stats.regions().len());
+    let filtered = stats.filter_by_region("reserve");
+    assert_eq!(filtered.failed_allocations.len(), 1);
+}
@@ -0,0 +1,73 @@
+use rallo::{Capture, RalloAllocator, RawEventKind, TrackOptions, TrackingError};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+#[inline(never)]
+fn allocate(size: usize) -> Vec<u8> {
+    vec![0_u8; size]
+}
+
+#[test]
+fn test_streaming() {
+    let path = std::env::temp_dir().join(format!("rallo-test8-{}.bin", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+
+    // Small buffers: the writer empties them while the session goes on
+    let options = TrackOptions::new().max_log_count(64);
+    let guard = ALLOCATOR.stream(file, options).unwrap();
+    assert_eq!(
+        ALLOCATOR.start().err().unwrap(),
+        TrackingError::AlreadyTracking
+    );
+    assert_eq!(
+        ALLOCATOR.collect().unwrap_err(),
+        TrackingError::StillTracking
+    );
+    for _ in 0..10 {
+        for _ in 0..20 {
+            drop(allocate(12345));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    guard.finish().unwrap();
+
+    let capture = Capture::read_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let streamed = |kind| {
+        capture
+            .events
+            .iter()
+            .filter(|event| event.kind == kind && event.size == 12345)
+            .count()
+            + capture.dropped_events
+    };
+    // More events than the buffers hold
+    assert!(streamed(RawEventKind::Allocation) >= 200);
+    assert!(streamed(RawEventKind::Deallocation) >= 200);
+    assert!(capture.counters.total_bytes_allocated >= 200 * 12345);
+
+    let stats = capture.into_stats();
+    let allocation = stats
+        .allocations
+        .iter()
+        .find(|allocation| allocation.allocation_size == 12345)
+        .unwrap();
+    assert!(allocation.stack.iter().any(|frame| {
+        frame
+            .fn_name
+            .as_deref()
+            .is_some_and(|name| name.contains("test8") && name.contains("allocate"))
+    }));
+    assert!(
+        stats
+            .allocations
+            .iter()
+            .zip(stats.allocations.iter().skip(1))
+            .all(|(newer, older)| newer.sequence > older.sequence)
+    );
+
+    // The allocator is ready for another session
+    ALLOCATOR.track(|| drop(allocate(8))).unwrap();
+}
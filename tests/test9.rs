@@ -0,0 +1,66 @@
+#![cfg(unix)]
+
+use std::{path::Path, time::Duration};
+
+use rallo::{RalloAllocator, ReportFormat, TrackOptions, TrackingError};
+
+#[global_allocator]
+static ALLOCATOR: RalloAllocator = RalloAllocator::new();
+
+fn send(signal: libc::c_int) {
+    assert_eq!(unsafe { libc::kill(libc::getpid(), signal) }, 0);
+}
+
+/// Wait for the helper thread to handle a signal
+fn wait_for<F: Fn() -> bool>(condition: F) {
+    for _ in 0..3000 {
+        if condition() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the signal was not handled");
+}
+
+fn report(dir: &Path, n: usize) -> std::path::PathBuf {
+    dir.join(format!("rallo-{}-{n}.html", std::process::id()))
+}
+
+#[test]
+fn test_signals() {
+    let dir = std::env::temp_dir().join(format!("rallo-test9-{}", std::process::id()));
+    ALLOCATOR
+        .install_signal_handlers(&dir, ReportFormat::Html, TrackOptions::new())
+        .unwrap();
+    let error = ALLOCATOR
+        .install_signal_handlers(&dir, ReportFormat::Html, TrackOptions::new())
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+
+    // Start tracking
+    send(libc::SIGUSR1);
+    wait_for(|| {
+        drop(Box::new(0_u8));
+        ALLOCATOR
+            .snapshot()
+            .is_ok_and(|stats| stats.counters.total_allocations > 0)
+    });
+    let v = vec![0_u8; 4321];
+
+    // Report while tracking
+    send(libc::SIGUSR2);
+    wait_for(|| report(&dir, 1).exists());
+    let html = std::fs::read_to_string(report(&dir, 1)).unwrap();
+    assert!(html.contains("4321"));
+
+    // Stop tracking, then report the stopped session
+    send(libc::SIGUSR1);
+    // Polled with snapshots: collecting would take the stats of the helper thread
+    wait_for(|| ALLOCATOR.snapshot().err() == Some(TrackingError::NotStarted));
+    send(libc::SIGUSR2);
+    wait_for(|| report(&dir, 2).exists());
+    drop(v);
+
+    ALLOCATOR.track(|| {}).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}